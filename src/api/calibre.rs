@@ -1,48 +1,242 @@
-use axum::{Json, extract::State};
-use log::error;
+use std::time::Duration;
+
+use anyhow::Result;
+use axum::{Json, extract::State, http::StatusCode};
+use log::{error, warn};
+use serde_json::{Value, json};
 
 use super::{
-    BookMetadataReplaceRequest, BookMetadataReplaceResponse, MetadataUpdateResponse,
-    utils::calibre::{replace_book_metadata, update_metadata},
+    BookMetadataReplaceRequest, BookMetadataReplaceResponse, MetadataRevertRequest,
+    MetadataRevertResponse, MetadataUpdateResponse,
+    utils::calibre::{apply_book_metadata, fetch_book_metadata, revert_metadata, update_metadata},
+};
+use crate::{
+    DownloadManager,
+    archive_db::{TaskKind, TaskState},
+    tag_db::db::EhTagDb,
 };
-use crate::DownloadManager;
 
 pub async fn handle_metadata_update(
     State(manager): State<DownloadManager>,
-) -> Json<MetadataUpdateResponse> {
-    let calibre_client = manager.calibre_client.clone();
-    let tag_db = manager.tag_db;
-
-    tokio::spawn(async move {
-        if let Err(e) = update_metadata(calibre_client, tag_db).await {
-            error!("Failed to update metadata: {e:?}");
-        }
-    });
+) -> Result<Json<MetadataUpdateResponse>, (StatusCode, Json<Value>)> {
+    let task_id = manager
+        .create_task(TaskKind::MetadataUpdate, "", None, None)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"msg": format!("启动元数据翻译更新任务失败: {}", e)})),
+            )
+        })?;
+    manager.spawn_metadata_update(task_id).await;
 
-    Json(MetadataUpdateResponse {
+    Ok(Json(MetadataUpdateResponse {
         message: "元数据翻译更新任务已启动".to_string(),
-    })
+    }))
+}
+
+/// Switches the library to another display mode, `raw` by default. The mode
+/// is kept for books added and translated later.
+pub async fn handle_metadata_revert(
+    State(manager): State<DownloadManager>,
+    request: Option<Json<MetadataRevertRequest>>,
+) -> Result<Json<MetadataRevertResponse>, (StatusCode, Json<Value>)> {
+    let Json(request) = request.unwrap_or_default();
+    let task_id = async {
+        manager
+            .archive_db
+            .lock()
+            .await
+            .set_display_mode(request.mode)?;
+        manager
+            .create_task(TaskKind::MetadataRevert, "", None, None)
+            .await
+    }
+    .await
+    .map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(json!({"msg": format!("启动元数据显示模式切换任务失败: {}", e)})),
+        )
+    })?;
+    manager.spawn_metadata_revert(task_id).await;
+
+    Ok(Json(MetadataRevertResponse {
+        message: format!("元数据显示模式切换任务已启动: {}", request.mode.as_str()),
+    }))
 }
 
 pub async fn handle_book_metadata_replace(
     State(manager): State<DownloadManager>,
     Json(request): Json<BookMetadataReplaceRequest>,
-) -> Json<BookMetadataReplaceResponse> {
-    let is_exhentai = manager.is_exhentai;
-    let calibre_client = manager.calibre_client.clone();
-    let client = manager.client.clone();
-    let tag_db = manager.tag_db;
-    let url = request.url;
-
-    tokio::spawn(async move {
-        if let Err(e) =
-            replace_book_metadata(calibre_client, tag_db, client, is_exhentai, url.clone()).await
-        {
-            error!("Failed to replace book metadata for URL {url}: {e:?}");
-        }
-    });
+) -> Result<Json<BookMetadataReplaceResponse>, (StatusCode, Json<Value>)> {
+    let task_id = manager
+        .create_task(TaskKind::MetadataReplace, &request.url, None, None)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"msg": format!("启动书籍元数据替换任务失败: {}", e)})),
+            )
+        })?;
+    manager
+        .spawn_book_metadata_replace(task_id, request.url)
+        .await;
 
-    Json(BookMetadataReplaceResponse {
+    Ok(Json(BookMetadataReplaceResponse {
         message: "书籍元数据替换任务已启动".to_string(),
-    })
+    }))
+}
+
+impl DownloadManager {
+    /// Applies a new tag translation release, if any, reloads the alias
+    /// file and optionally retranslates the calibre library with them.
+    pub(crate) async fn refresh_tag_db(&self, update_metadata: bool) -> Result<()> {
+        let updated = EhTagDb::refresh(&self.tag_db, &self.tag_db_source).await;
+        // Aliases are local, so they are loaded even if the source is unreachable
+        let aliases_changed = match &self.tag_alias_file {
+            Some(path) => EhTagDb::load_aliases(&self.tag_db, path).await?,
+            None => false,
+        };
+        if !(updated? || aliases_changed) || !update_metadata {
+            return Ok(());
+        }
+        let task_id = self
+            .create_task(TaskKind::MetadataUpdate, "", None, None)
+            .await?;
+        self.spawn_metadata_update(task_id).await;
+        Ok(())
+    }
+
+    /// Refreshes the tag database at startup, falling back to the stored
+    /// version if the source can't be reached. Fails if there is none.
+    pub async fn init_tag_db(&self) -> Result<()> {
+        let Err(e) = self.refresh_tag_db(false).await else {
+            return Ok(());
+        };
+        match self.tag_db.lock().await.get_stored_version()? {
+            Some(version) => {
+                warn!(
+                    "Failed to refresh tag translations, continuing with version {version}: {e:?}"
+                );
+                Ok(())
+            }
+            None => Err(e.context(
+                "Failed to initialize tag database: the tag source can't be read and no \
+                translations have been stored yet",
+            )),
+        }
+    }
+
+    pub fn spawn_tag_refresh_job(&self, interval: u64, update_metadata: bool) {
+        if interval == 0 {
+            return;
+        }
+        let manager = self.clone();
+
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(Duration::from_secs(interval));
+            // The first tick completes immediately, the database was just refreshed
+            timer.tick().await;
+            loop {
+                timer.tick().await;
+                if let Err(e) = manager.refresh_tag_db(update_metadata).await {
+                    error!("Failed to refresh tag translations: {e:?}");
+                }
+            }
+        });
+    }
+
+    pub(crate) async fn spawn_metadata_update(&self, task_id: String) {
+        let manager = self.clone();
+        let id = task_id.clone();
+
+        let handle = tokio::spawn(async move {
+            let result = async {
+                manager
+                    .enter_stage(&task_id, TaskState::AddingToCalibre)
+                    .await?;
+                update_metadata(
+                    manager.calibre.clone(),
+                    manager.tag_db.clone(),
+                    manager.archive_db.clone(),
+                    &manager.mapping,
+                )
+                .await
+            }
+            .await;
+            if let Err(e) = &result {
+                error!("Failed to update metadata: {e:?}");
+            }
+            manager.finish_task(&task_id, &result).await;
+        });
+        self.track_task_handle(&id, handle.abort_handle()).await;
+    }
+
+    pub(crate) async fn spawn_metadata_revert(&self, task_id: String) {
+        let manager = self.clone();
+        let id = task_id.clone();
+
+        let handle = tokio::spawn(async move {
+            let result = async {
+                manager
+                    .enter_stage(&task_id, TaskState::AddingToCalibre)
+                    .await?;
+                let display_mode = manager.archive_db.lock().await.get_display_mode()?;
+                revert_metadata(
+                    manager.calibre.clone(),
+                    manager.archive_db.clone(),
+                    manager.tag_db.clone(),
+                    display_mode,
+                    &manager.mapping,
+                )
+                .await
+            }
+            .await;
+            if let Err(e) = &result {
+                error!("Failed to revert metadata: {e:?}");
+            }
+            manager.finish_task(&task_id, &result).await;
+        });
+        self.track_task_handle(&id, handle.abort_handle()).await;
+    }
+
+    pub(crate) async fn spawn_book_metadata_replace(&self, task_id: String, url: String) {
+        let manager = self.clone();
+        let id = task_id.clone();
+
+        let handle = tokio::spawn(async move {
+            let result = async {
+                manager
+                    .enter_stage(&task_id, TaskState::FetchingMetadata)
+                    .await?;
+                let (book_id, metadata) = fetch_book_metadata(
+                    &manager.calibre,
+                    &manager.client,
+                    manager.is_exhentai,
+                    &url,
+                )
+                .await?;
+                manager
+                    .enter_stage(&task_id, TaskState::AddingToCalibre)
+                    .await?;
+                apply_book_metadata(
+                    manager.calibre.clone(),
+                    manager.tag_db.clone(),
+                    manager.archive_db.clone(),
+                    manager.is_exhentai,
+                    &manager.mapping,
+                    book_id,
+                    metadata,
+                )
+                .await
+            }
+            .await;
+            if let Err(e) = &result {
+                error!("Failed to replace book metadata for URL {url}: {e:?}");
+            }
+            manager.finish_task(&task_id, &result).await;
+        });
+        self.track_task_handle(&id, handle.abort_handle()).await;
+    }
 }
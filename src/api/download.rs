@@ -1,27 +1,56 @@
-use std::path::PathBuf;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
 
 use anyhow::{Result, anyhow};
 use axum::{Json, extract::State, http::StatusCode};
-use libeh::dto::{
-    api::{GIDListItem, GalleryMetadataRequest, GalleryMetadataResponse},
-    gallery::detail::GalleryDetail,
-};
+use libeh::dto::{api::GalleryMetadata, gallery::detail::GalleryDetail};
 use log::{error, info, warn};
 use reqwest::Url;
 use serde_json::{Value, json};
 
 use super::{
-    DownloadRequest, DownloadType,
-    utils::{calibre::add_to_calibre, extract_cover},
+    BatchDownloadRequest, BatchDownloadResponse, BatchDownloadResult, BatchDownloadStatus,
+    DownloadRequest, DownloadType, OnExisting,
+    utils::{
+        archive::write_archive,
+        calibre::{
+            add_to_calibre, apply_book_metadata, find_book_id, gallery_identifier,
+            replace_book_file,
+        },
+        extract_cover, fetch_gallery_metadata, fetch_gallery_metadata_batch, parse_gallery_url,
+        parse_size,
+        retry::{PermanentError, eh_error},
+        validate_archive,
+    },
 };
-use crate::{DownloadManager, api::EH_API_URL, g_info, g_warn};
+use crate::{
+    DownloadManager,
+    archive_db::{TaskKind, TaskState},
+    g_info, g_warn,
+};
+
+enum ExistingBook {
+    Absent,
+    Handled,
+    ReplaceFile(i32),
+}
+
+pub async fn handle_batch_download(
+    State(manager): State<DownloadManager>,
+    Json(request): Json<BatchDownloadRequest>,
+) -> Json<BatchDownloadResponse> {
+    let results = manager.download_batch(request.items).await;
+    Json(BatchDownloadResponse { results })
+}
 
 pub async fn handle_download(
     State(manager): State<DownloadManager>,
     Json(request): Json<DownloadRequest>,
 ) -> (StatusCode, Json<Value>) {
     match manager
-        .download_and_archive(request.url, request.download_type)
+        .download_and_archive(request.url, request.download_type, request.on_existing)
         .await
     {
         Ok(_) => (StatusCode::OK, Json(json!({}))),
@@ -33,141 +62,436 @@ pub async fn handle_download(
 }
 
 impl DownloadManager {
-    async fn download_and_archive(&self, url: String, download_type: DownloadType) -> Result<()> {
-        let is_exhentai = self.is_exhentai;
+    async fn download_and_archive(
+        &self,
+        url: String,
+        download_type: DownloadType,
+        on_existing: OnExisting,
+    ) -> Result<()> {
+        let url = self.normalize_url(&url);
 
-        let url = if is_exhentai {
-            url.replace("e-hentai.org", "exhentai.org")
-        } else {
-            url.replace("exhentai.org", "e-hentai.org")
-        };
+        if self.is_download_in_progress(&url).await {
+            warn!("Download job is already in progress: {url}");
+            return Err(anyhow!("Download job is already in progress: {}", url));
+        }
 
-        {
-            let tasks = self.active_tasks.lock().await;
-            if tasks.contains(&url) {
-                warn!("Download job is already in progress: {url}");
-                return Err(anyhow!("Download job is already in progress: {}", url));
+        self.queue_download(url, download_type, on_existing, None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn queue_download(
+        &self,
+        url: String,
+        download_type: DownloadType,
+        on_existing: OnExisting,
+        metadata: Option<GalleryMetadata>,
+    ) -> Result<String> {
+        let task_id = self
+            .create_task(TaskKind::Download, &url, Some(download_type), None)
+            .await?;
+        self.update_task(&task_id, |status| status.on_existing = Some(on_existing))
+            .await;
+
+        self.spawn_download(task_id.clone(), url, download_type, on_existing, metadata)
+            .await;
+
+        Ok(task_id)
+    }
+
+    async fn download_batch(&self, items: Vec<DownloadRequest>) -> Vec<BatchDownloadResult> {
+        let mut results = Vec::with_capacity(items.len());
+        let mut pending = Vec::new();
+        let mut seen = HashSet::new();
+
+        for item in items {
+            let url = self.normalize_url(&item.url);
+            let status = match parse_gallery_url(&url) {
+                None => Some((
+                    BatchDownloadStatus::Invalid,
+                    Some("无效的画廊链接".to_string()),
+                )),
+                Some((gid, _)) if !seen.insert(gid) => Some((BatchDownloadStatus::Duplicate, None)),
+                Some(_) if self.is_download_in_progress(&url).await => {
+                    Some((BatchDownloadStatus::Duplicate, None))
+                }
+                Some(_) => None,
+            };
+            let index = results.len();
+            let (status, msg) = status.unwrap_or((BatchDownloadStatus::Queued, None));
+            if status == BatchDownloadStatus::Queued {
+                pending.push((index, url.clone(), item.download_type, item.on_existing));
             }
+            results.push(BatchDownloadResult {
+                url,
+                status,
+                task_id: None,
+                book_id: None,
+                msg,
+            });
         }
 
-        let semaphore = self.semaphore.clone();
-        let client = self.client.clone();
-        let output = self.output.clone();
-        let calibre_client = self.calibre_client.clone();
-        let tag_db = self.tag_db.clone();
-        let active_tasks = self.active_tasks.clone();
+        // Fetched in groups so a large batch doesn't cost one API call per gallery
+        let urls: Vec<String> = pending.iter().map(|(_, url, _, _)| url.clone()).collect();
+        let mut metadata: Option<HashMap<i64, GalleryMetadata>> =
+            match fetch_gallery_metadata_batch(&self.client, &urls).await {
+                Ok(list) => Some(list.into_iter().map(|m| (m.gid, m)).collect()),
+                Err(e) => {
+                    // The jobs fetch the metadata themselves, with retries
+                    warn!("Failed to fetch metadata for download batch: {e}");
+                    None
+                }
+            };
+
+        for (index, url, download_type, on_existing) in pending {
+            let result = &mut results[index];
+            let (gid, token) = parse_gallery_url(&url).unwrap();
 
-        tokio::spawn(async move {
-            let _permit = semaphore.acquire().await.unwrap();
+            let gallery_metadata = metadata.as_mut().and_then(|m| m.remove(&gid));
+            if metadata.is_some() && gallery_metadata.is_none() {
+                result.status = BatchDownloadStatus::Invalid;
+                result.msg = Some("画廊不存在".to_string());
+                continue;
+            }
 
+            let identifier = gallery_identifier(gid, &token, self.is_exhentai);
+            match find_book_id(&self.calibre, &identifier).await {
+                Ok(Some(book_id)) if on_existing == OnExisting::Skip => {
+                    result.status = BatchDownloadStatus::AlreadyInLibrary;
+                    result.book_id = Some(book_id);
+                    continue;
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to look up {identifier} in calibre: {e}"),
+            }
+
+            match self
+                .queue_download(url, download_type, on_existing, gallery_metadata)
+                .await
             {
-                let mut tasks = active_tasks.lock().await;
-                tasks.insert(url.clone());
+                Ok(task_id) => result.task_id = Some(task_id),
+                Err(e) => {
+                    result.status = BatchDownloadStatus::Invalid;
+                    result.msg = Some(format!("启动下载任务失败: {}", e));
+                }
+            }
+        }
+
+        results
+    }
+
+    fn normalize_url(&self, url: &str) -> String {
+        if self.is_exhentai {
+            url.replace("e-hentai.org", "exhentai.org")
+        } else {
+            url.replace("exhentai.org", "e-hentai.org")
+        }
+    }
+
+    async fn is_download_in_progress(&self, url: &str) -> bool {
+        let tasks = self.active_tasks.lock().await;
+        tasks
+            .values()
+            .any(|t| t.url == url && !t.state.is_finished())
+    }
+
+    pub(crate) async fn spawn_download(
+        &self,
+        task_id: String,
+        url: String,
+        download_type: DownloadType,
+        on_existing: OnExisting,
+        metadata: Option<GalleryMetadata>,
+    ) {
+        let manager = self.clone();
+        let id = task_id.clone();
+
+        let handle = tokio::spawn(async move {
+            let _permit = manager.semaphore.acquire().await.unwrap();
+
+            let result = manager
+                .run_download(&task_id, &url, download_type, on_existing, metadata)
+                .await;
+            if let Err(e) = &result {
+                error!("Download job failed for URL {url}: {e:?}");
             }
+            manager.finish_task(&task_id, &result).await;
+        });
+        self.track_task_handle(&id, handle.abort_handle()).await;
+    }
+
+    async fn run_download(
+        &self,
+        task_id: &str,
+        url: &str,
+        download_type: DownloadType,
+        on_existing: OnExisting,
+        metadata: Option<GalleryMetadata>,
+    ) -> Result<()> {
+        let client = &self.client;
+        let is_exhentai = self.is_exhentai;
+
+        self.enter_stage(task_id, TaskState::FetchingMetadata)
+            .await?;
 
-            let result: Result<()> = async {
-                info!("Starting download: {url} (type: {download_type})");
+        info!("Starting download: {url} (type: {download_type})");
+
+        let mut metadata = metadata;
+        let upgrade_book_id = {
+            let tasks = self.active_tasks.lock().await;
+            tasks
+                .get(task_id)
+                .filter(|t| t.kind == TaskKind::Upgrade)
+                .and_then(|t| t.book_id)
+        };
+        let existing_book_id = match upgrade_book_id {
+            Some(book_id) => Some(book_id),
+            None => match self
+                .check_existing_book(task_id, url, on_existing, &mut metadata)
+                .await?
+            {
+                ExistingBook::Absent => None,
+                ExistingBook::Handled => return Ok(()),
+                ExistingBook::ReplaceFile(book_id) => Some(book_id),
+            },
+        };
 
+        let detail = self
+            .with_retry(task_id, self.metadata_retry, || async move {
+                let page_url = Url::parse(url).map_err(|e| PermanentError(e.to_string()))?;
                 let html = client
-                    .get_html(Url::parse(&url)?)
+                    .get_html(page_url)
                     .await
-                    .map_err(|e| anyhow!(e))?;
-                let detail = GalleryDetail::parse(html).map_err(|e| anyhow!(e))?;
-                let gid_token = format!("{}_{}", detail.info.gid, detail.info.token);
-                g_info!(
-                    gid_token,
-                    "Gallery details parsed successfully. Title: {}, Size: {}",
-                    detail.info.title,
-                    detail.size
-                );
+                    .map_err(|e| eh_error(anyhow!(e)))?;
+                let detail =
+                    GalleryDetail::parse(html).map_err(|e| PermanentError(e.to_string()))?;
+                Ok(detail)
+            })
+            .await?;
+        let gid_token = format!("{}_{}", detail.info.gid, detail.info.token);
+        g_info!(
+            gid_token,
+            "Gallery details parsed successfully. Title: {}, Size: {}",
+            detail.info.title,
+            detail.size
+        );
 
-                let body = GalleryMetadataRequest::new(vec![GIDListItem::from(url.clone())]);
-                let body = serde_json::to_string(&body).unwrap();
-                let api_url = Url::parse(EH_API_URL).unwrap();
-                let response: GalleryMetadataResponse = client
-                    .post_json(api_url, body)
-                    .await
-                    .map_err(|e| anyhow!(e))?;
-                let metadata = response
-                    .gmetadata
-                    .into_iter()
-                    .next()
-                    .ok_or_else(|| anyhow!("No metadata found"))?;
+        let metadata = match metadata {
+            Some(metadata) => metadata,
+            None => {
+                self.with_retry(task_id, self.metadata_retry, || {
+                    fetch_gallery_metadata(client, url)
+                })
+                .await?
+            }
+        };
+        g_info!(
+            gid_token,
+            "Gallery metadata parsed successfully. Title: {}",
+            metadata.title
+        );
+
+        let gallery_dir = format!("{}/{}", self.output.display(), gid_token);
+        let filename = &gid_token;
+        let output_path = format!("{gallery_dir}/{filename}.cbz");
+
+        // A book being replaced needs a fresh copy, the local one is what it
+        // was built from
+        if existing_book_id.is_none() && PathBuf::from(&output_path).exists() {
+            g_warn!(gid_token, "Archive already exists: {}", output_path);
+        } else {
+            let is_original = match download_type {
+                DownloadType::Original => true,
+                DownloadType::Resample => false,
+            };
+            self.enter_stage(task_id, TaskState::Downloading).await?;
+            tokio::fs::create_dir_all(&gallery_dir).await?;
+            let part_path = format!("{output_path}.part");
+            let expected_size = parse_size(&detail.size.to_string());
+            let size = self
+                .with_retry(task_id, self.download_retry, || {
+                    self.download_archive_to(task_id, url, is_original, expected_size, &part_path)
+                })
+                .await?;
+            g_info!(
+                gid_token,
+                "Archive download completed successfully ({} bytes)",
+                size
+            );
+
+            self.enter_stage(task_id, TaskState::Writing).await?;
+            if let Err(e) = validate_archive(&part_path) {
+                tokio::fs::remove_file(&part_path).await?;
+                return Err(e);
+            }
+            g_info!(gid_token, "Moving archive to: {}", output_path);
+            // Replaces an older local copy, if any
+            tokio::fs::rename(&part_path, &output_path).await?;
+            g_info!(gid_token, "Archive saved successfully: {}", output_path);
+        }
+
+        let json_path = format!("{gallery_dir}/gallery_detail.json");
+        g_info!(gid_token, "Saving gallery details to JSON: {}", json_path);
+        let json = serde_json::to_string_pretty(&metadata)?;
+        tokio::fs::write(&json_path, json).await?;
+        g_info!(gid_token, "Gallery details saved to JSON successfully");
+
+        self.enter_stage(task_id, TaskState::ExtractingCover)
+            .await?;
+        g_info!(gid_token, "Extracting cover image");
+        let result = extract_cover(&output_path, &gallery_dir)?;
+        if let Some((cover, cover_path)) = &result {
+            g_info!(gid_token, "Found cover image: {}", cover);
+            g_info!(gid_token, "Cover image saved to: {}", cover_path);
+        } else {
+            g_warn!(gid_token, "No cover image found in archive");
+        }
+
+        self.enter_stage(task_id, TaskState::AddingToCalibre)
+            .await?;
+        if let Some(book_id) = existing_book_id {
+            replace_book_file(
+                self.calibre.clone(),
+                book_id,
+                &output_path,
+                result.as_ref().map(|(_, cover_path)| cover_path.as_str()),
+                &gid_token,
+            )
+            .await?;
+            // An upgrade has its own gid and token, and maybe a new title
+            apply_book_metadata(
+                self.calibre.clone(),
+                self.tag_db.clone(),
+                self.archive_db.clone(),
+                is_exhentai,
+                &self.mapping,
+                book_id,
+                metadata,
+            )
+            .await?;
+            if upgrade_book_id.is_some() {
                 g_info!(
                     gid_token,
-                    "Gallery metadata parsed successfully. Title: {}",
-                    metadata.title
+                    "Book {book_id} upgraded to the new gallery version"
                 );
+            }
+            return Ok(());
+        }
+        let book_id = add_to_calibre(
+            self.calibre.clone(),
+            self.tag_db.clone(),
+            self.archive_db.clone(),
+            is_exhentai,
+            &self.mapping,
+            output_path,
+            metadata,
+            &gid_token,
+        )
+        .await?;
+        self.update_task(task_id, |status| status.book_id = book_id)
+            .await;
+        g_info!(gid_token, "Book added to calibre library successfully");
 
-                let gallery_dir = format!("{}/{}", output.display(), gid_token);
-                let filename = &gid_token;
-                let output_path = format!("{gallery_dir}/{filename}.cbz");
-
-                if PathBuf::from(&output_path).exists() {
-                    g_warn!(gid_token, "Archive already exists: {}", output_path);
-                } else {
-                    let is_original = match download_type {
-                        DownloadType::Original => true,
-                        DownloadType::Resample => false,
-                    };
-                    let data = detail
-                        .download_archive(&client, is_original)
-                        .await
-                        .map_err(|e| anyhow!(e))?;
-                    g_info!(
-                        gid_token,
-                        "Archive download completed successfully ({} bytes)",
-                        data.len()
-                    );
-                    tokio::fs::create_dir_all(&gallery_dir).await?;
-                    g_info!(gid_token, "Writing archive to: {}", output_path);
-                    tokio::fs::write(&output_path, data).await?;
-                    g_info!(gid_token, "Archive saved successfully: {}", output_path);
-                }
+        Ok(())
+    }
 
-                let json_path = format!("{gallery_dir}/gallery_detail.json");
-                g_info!(gid_token, "Saving gallery details to JSON: {}", json_path);
-                let json = serde_json::to_string_pretty(&metadata)?;
-                tokio::fs::write(&json_path, json).await?;
-                g_info!(gid_token, "Gallery details saved to JSON successfully");
-
-                g_info!(gid_token, "Extracting cover image");
-                let result = extract_cover(&output_path, &gallery_dir)?;
-                if let Some((cover, cover_path)) = result {
-                    g_info!(gid_token, "Found cover image: {}", cover);
-                    g_info!(gid_token, "Cover image saved to: {}", cover_path);
-                } else {
-                    g_warn!(gid_token, "No cover image found in archive");
-                }
+    /// Looks the gallery up in calibre and applies the `on_existing` policy.
+    async fn check_existing_book(
+        &self,
+        task_id: &str,
+        url: &str,
+        on_existing: OnExisting,
+        metadata: &mut Option<GalleryMetadata>,
+    ) -> Result<ExistingBook> {
+        let Some((gid, token)) = parse_gallery_url(url) else {
+            return Ok(ExistingBook::Absent);
+        };
+        let identifier = gallery_identifier(gid, &token, self.is_exhentai);
+        let Some(book_id) = find_book_id(&self.calibre, &identifier).await? else {
+            return Ok(ExistingBook::Absent);
+        };
+        self.update_task(task_id, |status| status.book_id = Some(book_id))
+            .await;
 
-                add_to_calibre(
-                    calibre_client,
-                    tag_db,
-                    is_exhentai,
-                    output_path,
+        match on_existing {
+            OnExisting::Skip => {
+                info!("Gallery is already in calibre as book {book_id}, skipping: {url}");
+                Ok(ExistingBook::Handled)
+            }
+            OnExisting::RefreshMetadata => {
+                info!(
+                    "Gallery is already in calibre as book {book_id}, refreshing metadata: {url}"
+                );
+                let metadata = match metadata.take() {
+                    Some(metadata) => metadata,
+                    None => {
+                        self.with_retry(task_id, self.metadata_retry, || {
+                            fetch_gallery_metadata(&self.client, url)
+                        })
+                        .await?
+                    }
+                };
+                self.enter_stage(task_id, TaskState::AddingToCalibre)
+                    .await?;
+                apply_book_metadata(
+                    self.calibre.clone(),
+                    self.tag_db.clone(),
+                    self.archive_db.clone(),
+                    self.is_exhentai,
+                    &self.mapping,
+                    book_id,
                     metadata,
-                    &gid_token,
                 )
                 .await?;
-                g_info!(gid_token, "Book added to calibre library successfully");
+                Ok(ExistingBook::Handled)
+            }
+            OnExisting::ReplaceFile => {
+                info!("Gallery is already in calibre as book {book_id}, replacing file: {url}");
+                Ok(ExistingBook::ReplaceFile(book_id))
+            }
+        }
+    }
 
-                {
-                    let mut tasks = active_tasks.lock().await;
-                    tasks.remove(&url);
-                }
+    async fn download_archive_to(
+        &self,
+        task_id: &str,
+        url: &str,
+        is_original: bool,
+        expected_size: Option<u64>,
+        part_path: &str,
+    ) -> Result<u64> {
+        let (gid, token) = parse_gallery_url(url)
+            .ok_or_else(|| PermanentError("Invalid URL format".to_string()))?;
+        let offset = match tokio::fs::metadata(part_path).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+        let saved_url = {
+            let tasks = self.active_tasks.lock().await;
+            tasks.get(task_id).and_then(|t| t.archive_url.clone())
+        };
 
-                Ok(())
+        let download = self
+            .archive_client
+            .open(gid, &token, is_original, saved_url.as_deref(), offset)
+            .await?;
+        match &download.resolved_url {
+            Some(archive_url) => {
+                self.update_task(task_id, |status| {
+                    status.archive_url = Some(archive_url.to_string())
+                })
+                .await;
             }
-            .await;
-            if let Err(e) = result {
-                {
-                    let mut tasks = active_tasks.lock().await;
-                    tasks.remove(&url);
-                }
-                error!("Download job failed for URL {url}: {e:?}");
-            }
-        });
+            None => info!(
+                "Resuming archive download for {url} from byte {}",
+                download.offset
+            ),
+        }
 
-        Ok(())
+        write_archive(download, expected_size, part_path, |received, total| {
+            self.set_task_progress(task_id, received, total)
+        })
+        .await
     }
 }
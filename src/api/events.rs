@@ -0,0 +1,25 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::State,
+    response::sse::{Event, KeepAlive, Sse},
+};
+use tokio_stream::{Stream, StreamExt, wrappers::BroadcastStream};
+
+use crate::DownloadManager;
+
+pub async fn handle_events(
+    State(manager): State<DownloadManager>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = manager.events.subscribe();
+    let stream = BroadcastStream::new(receiver).filter_map(|event| {
+        let event = event.ok()?;
+        Event::default()
+            .event(event.name())
+            .json_data(&event)
+            .ok()
+            .map(Ok)
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
@@ -1,17 +1,19 @@
-use std::{path::PathBuf, vec};
+use std::path::PathBuf;
 
 use anyhow::{Result, anyhow};
 use axum::{Json, extract::State, http::StatusCode};
-use libeh::dto::api::{GIDListItem, GalleryMetadataRequest, GalleryMetadataResponse};
 use log::{error, info, warn};
-use reqwest::Url;
 use serde_json::{Value, json};
 
 use super::{
     ImportRequest,
-    utils::{calibre::add_to_calibre, extract_cover},
+    utils::{calibre::add_to_calibre, extract_cover, fetch_gallery_metadata},
+};
+use crate::{
+    DownloadManager,
+    archive_db::{TaskKind, TaskState},
+    g_info, g_warn,
 };
-use crate::{DownloadManager, api::EH_API_URL, g_info, g_warn};
 
 pub async fn handle_import(
     State(manager): State<DownloadManager>,
@@ -26,94 +28,112 @@ pub async fn handle_import(
     }
 }
 
+pub(crate) fn check_archive(path: &str) -> Result<()> {
+    let archive = PathBuf::from(path);
+    if !archive.exists() || !archive.is_file() {
+        return Err(anyhow!("Archive not found: {}", path));
+    }
+
+    let ext = archive.extension().and_then(|e| e.to_str());
+    if ext != Some("zip") && ext != Some("cbz") {
+        return Err(anyhow!("File must be a .cbz or .zip archive"));
+    }
+
+    Ok(())
+}
+
 impl DownloadManager {
     pub async fn import_archive(&self, url: String, path: String) -> Result<()> {
-        let client = self.client.clone();
-        let output = self.output.clone();
-        let is_exhentai = self.is_exhentai;
-        let calibre_client = self.calibre_client.clone();
-        let tag_db = self.tag_db.clone();
-        let original_url = url.clone();
-
-        let archive = PathBuf::from(&path);
-        if !archive.exists() || !archive.is_file() {
-            return Err(anyhow!("Archive not found: {}", path));
+        check_archive(&path)?;
+
+        let task_id = self
+            .create_task(TaskKind::Import, &url, None, Some(path.clone()))
+            .await?;
+
+        self.spawn_import(task_id, url, path).await;
+
+        Ok(())
+    }
+
+    pub(crate) async fn spawn_import(&self, task_id: String, url: String, path: String) {
+        let manager = self.clone();
+        let id = task_id.clone();
+
+        let handle = tokio::spawn(async move {
+            let result = manager.run_import(&task_id, &url, &path).await;
+            if let Err(e) = &result {
+                error!("Import task failed for URL {url}: {e:?}");
+            }
+            manager.finish_task(&task_id, &result).await;
+        });
+        self.track_task_handle(&id, handle.abort_handle()).await;
+    }
+
+    async fn run_import(&self, task_id: &str, url: &str, path: &str) -> Result<()> {
+        self.enter_stage(task_id, TaskState::FetchingMetadata)
+            .await?;
+
+        info!("Starting import: {url} (file: {path})");
+
+        let metadata = self
+            .with_retry(task_id, self.metadata_retry, || {
+                fetch_gallery_metadata(&self.client, url)
+            })
+            .await?;
+        let gid_token = format!("{}_{}", metadata.gid, metadata.token);
+        g_info!(
+            gid_token,
+            "Gallery metadata parsed successfully. Title: {}",
+            metadata.title
+        );
+
+        let gallery_dir = format!("{}/{}", self.output.display(), gid_token);
+        let filename = &gid_token;
+        let output_path = format!("{gallery_dir}/{filename}.cbz");
+
+        if PathBuf::from(&output_path).exists() {
+            g_warn!(gid_token, "Archive already exists: {}", output_path);
+        } else {
+            self.enter_stage(task_id, TaskState::Writing).await?;
+            tokio::fs::create_dir_all(&gallery_dir).await?;
+            g_info!(gid_token, "Copying archive file to: {}", output_path);
+            tokio::fs::copy(path, &output_path).await?;
+            g_info!(gid_token, "File copied successfully: {}", output_path);
         }
 
-        let ext = archive.extension().and_then(|e| e.to_str());
-        if ext != Some("zip") && ext != Some("cbz") {
-            return Err(anyhow!("File must be a .cbz or .zip archive"));
+        let json_path = format!("{gallery_dir}/gallery_metadata.json");
+        g_info!(gid_token, "Saving gallery metadata to JSON: {}", json_path);
+        let json = serde_json::to_string_pretty(&metadata)?;
+        tokio::fs::write(&json_path, json).await?;
+        g_info!(gid_token, "Gallery details saved to JSON successfully");
+
+        self.enter_stage(task_id, TaskState::ExtractingCover)
+            .await?;
+        g_info!(gid_token, "Extracting cover image");
+        let result = extract_cover(&output_path, &gallery_dir)?;
+        if let Some((cover, cover_path)) = result {
+            g_info!(gid_token, "Found cover image: {}", cover);
+            g_info!(gid_token, "Cover image saved to: {}", cover_path);
+        } else {
+            g_warn!(gid_token, "No cover image found in archive");
         }
 
-        tokio::spawn(async move {
-            let result: Result<()> = async {
-                info!("Starting import: {url} (file: {path})");
-
-                let body = GalleryMetadataRequest::new(vec![GIDListItem::from(url)]);
-                let body = serde_json::to_string(&body).unwrap();
-                let api_url = Url::parse(EH_API_URL).unwrap();
-                let response: GalleryMetadataResponse = client
-                    .post_json(api_url, body)
-                    .await
-                    .map_err(|e| anyhow!(e))?;
-                let metadata = response
-                    .gmetadata
-                    .into_iter()
-                    .next()
-                    .ok_or_else(|| anyhow!("No metadata found"))?;
-                let gid_token = format!("{}_{}", metadata.gid, metadata.token);
-                g_info!(
-                    gid_token,
-                    "Gallery metadata parsed successfully. Title: {}",
-                    metadata.title
-                );
-
-                let gallery_dir = format!("{}/{}", output.display(), gid_token);
-                let filename = &gid_token;
-                let output_path = format!("{gallery_dir}/{filename}.cbz");
-
-                if PathBuf::from(&output_path).exists() {
-                    g_warn!(gid_token, "Archive already exists: {}", output_path);
-                } else {
-                    tokio::fs::create_dir_all(&gallery_dir).await?;
-                    g_info!(gid_token, "Copying archive file to: {}", output_path);
-                    tokio::fs::copy(&path, &output_path).await?;
-                    g_info!(gid_token, "File copied successfully: {}", output_path);
-                }
-
-                let json_path = format!("{gallery_dir}/gallery_metadata.json");
-                g_info!(gid_token, "Saving gallery metadata to JSON: {}", json_path);
-                let json = serde_json::to_string_pretty(&metadata)?;
-                tokio::fs::write(&json_path, json).await?;
-                g_info!(gid_token, "Gallery details saved to JSON successfully");
-
-                g_info!(gid_token, "Extracting cover image");
-                let result = extract_cover(&output_path, &gallery_dir)?;
-                if let Some((cover, cover_path)) = result {
-                    g_info!(gid_token, "Found cover image: {}", cover);
-                    g_info!(gid_token, "Cover image saved to: {}", cover_path);
-                } else {
-                    g_warn!(gid_token, "No cover image found in archive");
-                }
-
-                add_to_calibre(
-                    calibre_client,
-                    tag_db,
-                    is_exhentai,
-                    output_path,
-                    metadata,
-                    &gid_token,
-                )
-                .await?;
-                g_info!(gid_token, "Book added to calibre library successfully");
-
-                Ok(())
-            }
+        self.enter_stage(task_id, TaskState::AddingToCalibre)
+            .await?;
+        let book_id = add_to_calibre(
+            self.calibre.clone(),
+            self.tag_db.clone(),
+            self.archive_db.clone(),
+            self.is_exhentai,
+            &self.mapping,
+            output_path,
+            metadata,
+            &gid_token,
+        )
+        .await?;
+        self.update_task(task_id, |status| status.book_id = book_id)
             .await;
-            if let Err(e) = result {
-                error!("Import task failed for URL {original_url}: {e:?}");
-            }
-        });
+        g_info!(gid_token, "Book added to calibre library successfully");
 
         Ok(())
     }
@@ -1,40 +1,212 @@
 pub mod calibre;
 pub mod download;
+pub mod events;
 pub mod import;
+pub mod tag_overrides;
 pub mod tag_query;
 pub mod tasks;
-mod utils;
+pub(crate) mod utils;
+pub mod versions;
 
-use std::fmt::{self, Display};
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
 
 use serde::Deserialize;
 use serde::Serialize;
+use tokio::task::AbortHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::archive_db::{DisplayMode, TaskKind, TaskState};
+use crate::tag_db::TagFormat;
 
 pub const EH_API_URL: &str = "https://api.e-hentai.org/api.php";
+pub const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/121.0.0.0 Safari/537.36 Edg/121.0.0.0";
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum DownloadType {
     Original,
     Resample,
 }
 
+impl DownloadType {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Original => "original",
+            Self::Resample => "resample",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "original" => Some(Self::Original),
+            "resample" => Some(Self::Resample),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for DownloadType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s).ok_or_else(|| format!("Unknown download type: {s}"))
+    }
+}
+
 impl Display for DownloadType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{self:?}")
     }
 }
 
+/// What to do when the gallery is already in the calibre library.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnExisting {
+    #[default]
+    Skip,
+    ReplaceFile,
+    RefreshMetadata,
+}
+
+impl OnExisting {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Skip => "skip",
+            Self::ReplaceFile => "replace_file",
+            Self::RefreshMetadata => "refresh_metadata",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "skip" => Some(Self::Skip),
+            "replace_file" => Some(Self::ReplaceFile),
+            "refresh_metadata" => Some(Self::RefreshMetadata),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct DownloadRequest {
     pub url: String,
     pub download_type: DownloadType,
+    #[serde(default)]
+    pub on_existing: OnExisting,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BatchDownloadRequest {
+    pub items: Vec<DownloadRequest>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchDownloadStatus {
+    Queued,
+    Duplicate,
+    AlreadyInLibrary,
+    Invalid,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchDownloadResult {
+    pub url: String,
+    pub status: BatchDownloadStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub task_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub book_id: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub msg: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchDownloadResponse {
+    pub results: Vec<BatchDownloadResult>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStatus {
+    pub id: String,
+    pub kind: TaskKind,
+    pub url: String,
+    pub gid: Option<i64>,
+    pub token: Option<String>,
+    pub state: TaskState,
+    pub created_at: i64,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    pub error: Option<String>,
+    pub retries: u32,
+    pub book_id: Option<i32>,
+    pub bytes_received: u64,
+    pub bytes_total: Option<u64>,
+    #[serde(skip)]
+    pub download_type: Option<DownloadType>,
+    #[serde(skip)]
+    pub path: Option<String>,
+    #[serde(skip)]
+    pub archive_url: Option<String>,
+    #[serde(skip)]
+    pub on_existing: Option<OnExisting>,
+    #[serde(skip)]
+    pub abort_handle: Option<AbortHandle>,
+    /// Checked by the job before each stage.
+    #[serde(skip)]
+    pub cancel_token: CancellationToken,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TaskEvent {
+    Queued {
+        task: TaskStatus,
+    },
+    Stage {
+        id: String,
+        state: TaskState,
+    },
+    Progress {
+        id: String,
+        bytes_received: u64,
+        bytes_total: Option<u64>,
+    },
+    Completed {
+        id: String,
+        gid_token: Option<String>,
+        book_id: Option<i32>,
+    },
+    Failed {
+        id: String,
+        error: String,
+    },
+    Cancelled {
+        id: String,
+    },
+}
+
+impl TaskEvent {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Queued { .. } => "queued",
+            Self::Stage { .. } => "stage",
+            Self::Progress { .. } => "progress",
+            Self::Completed { .. } => "completed",
+            Self::Failed { .. } => "failed",
+            Self::Cancelled { .. } => "cancelled",
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
 pub struct ActiveTasksResponse {
     pub count: usize,
-    pub tasks: Vec<String>,
+    pub tasks: Vec<TaskStatus>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -52,11 +224,98 @@ pub struct MetadataUpdateResponse {
 pub struct TagQueryRequest {
     pub namespace: String,
     pub raw_tag: String,
+    #[serde(default)]
+    pub format: TagFormat,
 }
 
 #[derive(Debug, Serialize)]
 pub struct TagQueryResponse {
     pub translated_name: Option<String>,
+    pub translated_namespace: Option<String>,
+    pub name: Option<String>,
+    pub intro: Option<String>,
+    pub links: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagQueryBatchRequest {
+    pub tags: Vec<TagQueryItem>,
+    #[serde(default)]
+    pub format: TagFormat,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagQueryItem {
+    pub namespace: String,
+    pub raw_tag: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagQueryBatchItem {
+    pub namespace: String,
+    pub raw_tag: String,
+    #[serde(flatten)]
+    pub translation: TagQueryResponse,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagQueryBatchResponse {
+    pub results: Vec<TagQueryBatchItem>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagSearchRequest {
+    pub query: String,
+    #[serde(default)]
+    pub include_intro: bool,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagSearchResult {
+    pub namespace: String,
+    pub raw: String,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagSearchResponse {
+    pub results: Vec<TagSearchResult>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TagOverrideItem {
+    pub namespace: String,
+    pub raw_tag: String,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagOverrideListResponse {
+    pub overrides: Vec<TagOverrideItem>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MetadataRevertRequest {
+    #[serde(default = "default_revert_mode")]
+    pub mode: DisplayMode,
+}
+
+impl Default for MetadataRevertRequest {
+    fn default() -> Self {
+        Self {
+            mode: default_revert_mode(),
+        }
+    }
+}
+
+const fn default_revert_mode() -> DisplayMode {
+    DisplayMode::Raw
+}
+
+#[derive(Serialize)]
+pub struct MetadataRevertResponse {
+    pub message: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -68,3 +327,36 @@ pub struct BookMetadataReplaceRequest {
 pub struct BookMetadataReplaceResponse {
     pub message: String,
 }
+
+#[derive(Debug, Default, Deserialize)]
+pub struct VersionCheckRequest {
+    /// Archive type to download the newer versions with, only reports them if unset
+    #[serde(default)]
+    pub upgrade: Option<DownloadType>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OutdatedBook {
+    pub book_id: i32,
+    pub gid: i64,
+    pub token: String,
+    pub current_gid: i64,
+    pub current_token: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub task_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VersionCheckResponse {
+    pub message: String,
+    pub task_id: String,
+}
+
+/// The result of the last finished version check.
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionReport {
+    pub task_id: String,
+    pub finished_at: i64,
+    pub checked: usize,
+    pub outdated: Vec<OutdatedBook>,
+}
@@ -0,0 +1,109 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use log::{error, info};
+use serde_json::{Value, json};
+
+use super::{TagOverrideItem, TagOverrideListResponse};
+use crate::{DownloadManager, tag_db::TagOverride};
+
+pub async fn handle_tag_override_list(
+    State(manager): State<DownloadManager>,
+) -> (StatusCode, Json<Value>) {
+    let result = manager.tag_db.lock().await.get_overrides();
+
+    match result {
+        Ok(overrides) => {
+            let overrides = overrides
+                .into_iter()
+                .map(|tag| TagOverrideItem {
+                    namespace: tag.namespace,
+                    raw_tag: tag.raw,
+                    name: tag.name,
+                })
+                .collect();
+            (
+                StatusCode::OK,
+                Json(json!(TagOverrideListResponse { overrides })),
+            )
+        }
+        Err(e) => {
+            error!("Failed to list tag overrides: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": format!("获取翻译覆盖失败: {}", e)})),
+            )
+        }
+    }
+}
+
+pub async fn handle_tag_override_set(
+    State(manager): State<DownloadManager>,
+    Json(request): Json<TagOverrideItem>,
+) -> (StatusCode, Json<Value>) {
+    let namespace = request.namespace.trim();
+    let raw_tag = request.raw_tag.trim();
+    let name = request.name.trim();
+    if namespace.is_empty() || raw_tag.is_empty() || name.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "命名空间, 标签和翻译不能为空"})),
+        );
+    }
+    info!("Overriding translation of {namespace}:{raw_tag} with {name}");
+
+    let tag = TagOverride {
+        namespace: namespace.to_string(),
+        raw: raw_tag.to_string(),
+        name: name.to_string(),
+    };
+    let result = manager.tag_db.lock().await.set_override(&tag);
+
+    match result {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(json!(TagOverrideItem {
+                namespace: tag.namespace,
+                raw_tag: tag.raw,
+                name: tag.name,
+            })),
+        ),
+        Err(e) => {
+            error!("Failed to save tag override: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": format!("保存翻译覆盖失败: {}", e)})),
+            )
+        }
+    }
+}
+
+pub async fn handle_tag_override_delete(
+    State(manager): State<DownloadManager>,
+    Path((namespace, raw_tag)): Path<(String, String)>,
+) -> (StatusCode, Json<Value>) {
+    info!("Deleting translation override of {namespace}:{raw_tag}");
+
+    let result = manager
+        .tag_db
+        .lock()
+        .await
+        .delete_override(&namespace, &raw_tag);
+
+    match result {
+        Ok(true) => (StatusCode::OK, Json(json!({"message": "翻译覆盖已删除"}))),
+        Ok(false) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({"error": "翻译覆盖不存在"})),
+        ),
+        Err(e) => {
+            error!("Failed to delete tag override: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": format!("删除翻译覆盖失败: {}", e)})),
+            )
+        }
+    }
+}
@@ -3,8 +3,16 @@ use axum::{Json, extract::State, http::StatusCode};
 use log::{error, info};
 use serde_json::{Value, json};
 
-use super::{TagQueryRequest, TagQueryResponse};
-use crate::DownloadManager;
+use super::{
+    TagQueryBatchItem, TagQueryBatchRequest, TagQueryBatchResponse, TagQueryRequest,
+    TagQueryResponse, TagSearchRequest, TagSearchResponse, TagSearchResult,
+};
+use crate::{
+    DownloadManager,
+    tag_db::{TagFormat, db::EhTagDb},
+};
+
+const DEFAULT_SEARCH_LIMIT: usize = 50;
 
 pub async fn handle_tag_query(
     State(manager): State<DownloadManager>,
@@ -15,11 +23,18 @@ pub async fn handle_tag_query(
         request.namespace, request.raw_tag
     );
 
-    match query_tag_translation(&manager, &request.namespace, &request.raw_tag).await {
-        Ok(translated_name) => {
-            let response = TagQueryResponse { translated_name };
-            (StatusCode::OK, Json(json!(response)))
-        }
+    let result = {
+        let mut tag_db = manager.tag_db.lock().await;
+        query_tag_translation(
+            &mut tag_db,
+            &request.namespace,
+            &request.raw_tag,
+            request.format,
+        )
+    };
+
+    match result {
+        Ok(response) => (StatusCode::OK, Json(json!(response))),
         Err(e) => {
             error!("Failed to query tag translation: {e}");
             (
@@ -30,14 +45,105 @@ pub async fn handle_tag_query(
     }
 }
 
-async fn query_tag_translation(
-    manager: &DownloadManager,
-    namespace: &str,
-    raw_tag: &str,
-) -> Result<Option<String>> {
-    let result = {
+pub async fn handle_tag_query_batch(
+    State(manager): State<DownloadManager>,
+    Json(request): Json<TagQueryBatchRequest>,
+) -> (StatusCode, Json<Value>) {
+    info!("Querying tag translations for {} tags", request.tags.len());
+
+    let result: Result<Vec<TagQueryBatchItem>> = {
         let mut tag_db = manager.tag_db.lock().await;
-        tag_db.get_tag_name(namespace, raw_tag)?
+        request
+            .tags
+            .into_iter()
+            .map(|tag| {
+                let translation = query_tag_translation(
+                    &mut tag_db,
+                    &tag.namespace,
+                    &tag.raw_tag,
+                    request.format,
+                )?;
+                Ok(TagQueryBatchItem {
+                    namespace: tag.namespace,
+                    raw_tag: tag.raw_tag,
+                    translation,
+                })
+            })
+            .collect()
     };
-    Ok(result)
+
+    match result {
+        Ok(results) => (
+            StatusCode::OK,
+            Json(json!(TagQueryBatchResponse { results })),
+        ),
+        Err(e) => {
+            error!("Failed to query tag translations: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": format!("查询标签翻译失败: {}", e)})),
+            )
+        }
+    }
+}
+
+pub async fn handle_tag_search(
+    State(manager): State<DownloadManager>,
+    Json(request): Json<TagSearchRequest>,
+) -> (StatusCode, Json<Value>) {
+    let query = request.query.trim();
+    if query.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({"error": "搜索内容不能为空"})),
+        );
+    }
+    info!("Searching tags for: {query}");
+
+    let limit = request.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    let result = manager
+        .tag_db
+        .lock()
+        .await
+        .search_tags(query, request.include_intro, limit);
+
+    match result {
+        Ok(hits) => {
+            let results = hits
+                .into_iter()
+                .map(|hit| TagSearchResult {
+                    namespace: hit.namespace,
+                    raw: hit.raw,
+                    name: hit.name,
+                })
+                .collect();
+            (StatusCode::OK, Json(json!(TagSearchResponse { results })))
+        }
+        Err(e) => {
+            error!("Failed to search tags: {e}");
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"error": format!("搜索标签失败: {}", e)})),
+            )
+        }
+    }
+}
+
+fn query_tag_translation(
+    tag_db: &mut EhTagDb,
+    namespace: &str,
+    raw_tag: &str,
+    format: TagFormat,
+) -> Result<TagQueryResponse> {
+    let translated_name = tag_db.get_tag_name(namespace, raw_tag)?;
+    let translated_namespace = tag_db.get_namespace_name(namespace)?;
+    let tag = tag_db.get_tag(namespace, raw_tag, format)?;
+
+    Ok(TagQueryResponse {
+        translated_name,
+        translated_namespace,
+        name: tag.as_ref().map(|t| t.name.clone()),
+        intro: tag.as_ref().and_then(|t| t.intro.clone()),
+        links: tag.and_then(|t| t.links),
+    })
 }
@@ -1,16 +1,559 @@
-use axum::{Json, extract::State};
+use anyhow::{Result, anyhow};
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use chrono::Utc;
+use log::{error, info, warn};
+use serde_json::{Value, json};
+use tokio::task::AbortHandle;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
 
-use super::ActiveTasksResponse;
-use crate::DownloadManager;
+use super::{
+    ActiveTasksResponse, DownloadType, OnExisting, TaskEvent, TaskStatus,
+    import::check_archive,
+    utils::{
+        calibre::{find_book_id, gallery_identifier},
+        parse_gallery_url,
+        retry::{RetryPolicy, is_retryable},
+    },
+};
+use crate::{
+    DownloadManager,
+    archive_db::{Task, TaskKind, TaskState},
+};
 
 pub async fn get_active_tasks(State(manager): State<DownloadManager>) -> Json<ActiveTasksResponse> {
-    let tasks: Vec<String> = {
+    manager.prune_tasks().await;
+
+    let mut tasks: Vec<TaskStatus> = {
         let active_tasks = manager.active_tasks.lock().await;
-        active_tasks.iter().cloned().collect()
+        active_tasks.values().cloned().collect()
     };
+    tasks.sort_by_key(|t| t.created_at);
 
     Json(ActiveTasksResponse {
         count: tasks.len(),
         tasks,
     })
 }
+
+pub async fn handle_task_cancel(
+    State(manager): State<DownloadManager>,
+    Path(id): Path<String>,
+) -> Result<Json<TaskStatus>, (StatusCode, Json<Value>)> {
+    manager.cancel_task(&id).await.map(Json)
+}
+
+impl From<&TaskStatus> for Task {
+    fn from(status: &TaskStatus) -> Self {
+        Self {
+            id: status.id.clone(),
+            kind: status.kind.as_str().to_string(),
+            url: status.url.clone(),
+            download_type: status.download_type.map(|t| t.as_str().to_string()),
+            path: status.path.clone(),
+            state: status.state.as_str().to_string(),
+            error: status.error.clone(),
+            created_at: status.created_at,
+            updated_at: Utc::now().timestamp(),
+            gid: status.gid,
+            token: status.token.clone(),
+            started_at: status.started_at,
+            finished_at: status.finished_at,
+            archive_url: status.archive_url.clone(),
+            retries: status.retries as i32,
+            on_existing: status.on_existing.map(|o| o.as_str().to_string()),
+            book_id: status.book_id,
+        }
+    }
+}
+
+impl TryFrom<Task> for TaskStatus {
+    type Error = String;
+
+    fn try_from(task: Task) -> Result<Self, Self::Error> {
+        let kind = TaskKind::parse(&task.kind)
+            .ok_or_else(|| format!("Unknown task kind: {}", task.kind))?;
+        let state = TaskState::parse(&task.state)
+            .ok_or_else(|| format!("Unknown task state: {}", task.state))?;
+        let download_type = match task.download_type {
+            Some(t) => {
+                Some(DownloadType::parse(&t).ok_or_else(|| format!("Unknown download type: {t}"))?)
+            }
+            None => None,
+        };
+        let on_existing = match task.on_existing {
+            Some(o) => Some(
+                OnExisting::parse(&o).ok_or_else(|| format!("Unknown on_existing policy: {o}"))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            id: task.id,
+            kind,
+            url: task.url,
+            gid: task.gid,
+            token: task.token,
+            state,
+            created_at: task.created_at,
+            started_at: task.started_at,
+            finished_at: task.finished_at,
+            error: task.error,
+            bytes_received: 0,
+            bytes_total: None,
+            download_type,
+            path: task.path,
+            archive_url: task.archive_url,
+            retries: task.retries as u32,
+            book_id: task.book_id,
+            on_existing,
+            abort_handle: None,
+            cancel_token: CancellationToken::new(),
+        })
+    }
+}
+
+impl DownloadManager {
+    pub(crate) async fn create_task(
+        &self,
+        kind: TaskKind,
+        url: &str,
+        download_type: Option<DownloadType>,
+        path: Option<String>,
+    ) -> Result<String> {
+        let (gid, token) = match parse_gallery_url(url) {
+            Some((gid, token)) => (Some(gid), Some(token)),
+            None => (None, None),
+        };
+        let status = TaskStatus {
+            id: Uuid::new_v4().to_string(),
+            kind,
+            url: url.to_string(),
+            gid,
+            token,
+            state: TaskState::Queued,
+            created_at: Utc::now().timestamp(),
+            started_at: None,
+            finished_at: None,
+            error: None,
+            bytes_received: 0,
+            bytes_total: None,
+            download_type,
+            path,
+            archive_url: None,
+            retries: 0,
+            book_id: None,
+            on_existing: None,
+            abort_handle: None,
+            cancel_token: CancellationToken::new(),
+        };
+
+        self.archive_db
+            .lock()
+            .await
+            .save_task(&Task::from(&status))?;
+
+        let id = status.id.clone();
+        self.active_tasks
+            .lock()
+            .await
+            .insert(id.clone(), status.clone());
+        self.send_event(TaskEvent::Queued { task: status });
+
+        Ok(id)
+    }
+
+    /// Moves a job on to its next stage, or fails if it was cancelled. The
+    /// check and the move happen under the same lock as `cancel_task`.
+    pub(crate) async fn enter_stage(&self, id: &str, state: TaskState) -> Result<()> {
+        let mut cancelled = false;
+        self.update_task(id, |status| {
+            cancelled = status.cancel_token.is_cancelled();
+            if !cancelled {
+                apply_state(status, state);
+            }
+        })
+        .await;
+        if cancelled {
+            return Err(anyhow!("Task {id} was cancelled"));
+        }
+
+        self.send_event(TaskEvent::Stage {
+            id: id.to_string(),
+            state,
+        });
+        Ok(())
+    }
+
+    /// Callers throttle this to the rate progress events should be sent at.
+    pub(crate) async fn set_task_progress(&self, id: &str, received: u64, total: Option<u64>) {
+        {
+            let mut active_tasks = self.active_tasks.lock().await;
+            let Some(status) = active_tasks.get_mut(id) else {
+                return;
+            };
+            status.bytes_received = received;
+            status.bytes_total = total;
+        }
+
+        self.send_event(TaskEvent::Progress {
+            id: id.to_string(),
+            bytes_received: received,
+            bytes_total: total,
+        });
+    }
+
+    pub(crate) async fn finish_task(&self, id: &str, result: &Result<()>) {
+        let state = match result {
+            Ok(_) => TaskState::Done,
+            Err(_) => TaskState::Failed,
+        };
+        let mut cancelled = None;
+        self.update_task(id, |status| {
+            // The job only noticed the cancellation at its next stage
+            if status.state == TaskState::Cancelled {
+                cancelled = Some(status.clone());
+                return;
+            }
+            if let Err(e) = result {
+                status.error = Some(e.to_string());
+            }
+            apply_state(status, state);
+        })
+        .await;
+        if let Some(status) = cancelled {
+            info!("Task {id} stopped after being cancelled");
+            // Left to the job, it may have been using the files until now
+            self.cleanup_cancelled_task(&status).await;
+            return;
+        }
+
+        match result {
+            Ok(_) => {
+                let status = self.active_tasks.lock().await.remove(id);
+                if let Some(status) = status {
+                    self.send_event(TaskEvent::Completed {
+                        id: status.id,
+                        gid_token: status
+                            .gid
+                            .zip(status.token)
+                            .map(|(g, t)| format!("{g}_{t}")),
+                        book_id: status.book_id,
+                    });
+                }
+            }
+            Err(e) => {
+                self.send_event(TaskEvent::Failed {
+                    id: id.to_string(),
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    pub(crate) async fn track_task_handle(&self, id: &str, handle: AbortHandle) {
+        let mut active_tasks = self.active_tasks.lock().await;
+        match active_tasks.get_mut(id) {
+            // Cancelled before the job was even spawned
+            Some(status) if status.state == TaskState::Cancelled => handle.abort(),
+            Some(status) => status.abort_handle = Some(handle),
+            None => {}
+        }
+    }
+
+    async fn cancel_task(&self, id: &str) -> Result<TaskStatus, (StatusCode, Json<Value>)> {
+        let (status, previous, stopped) = {
+            let mut active_tasks = self.active_tasks.lock().await;
+            let Some(status) = active_tasks.get_mut(id) else {
+                return Err((StatusCode::NOT_FOUND, Json(json!({"msg": "任务不存在"}))));
+            };
+            if status.state.is_finished() {
+                return Err((
+                    StatusCode::CONFLICT,
+                    Json(json!({"msg": format!("任务已结束: {}", status.state)})),
+                ));
+            }
+            // Calibre writes are not transactional, let them complete
+            if status.state == TaskState::AddingToCalibre {
+                return Err((
+                    StatusCode::CONFLICT,
+                    Json(json!({"msg": "任务正在写入 Calibre，无法取消"})),
+                ));
+            }
+            status.cancel_token.cancel();
+            let previous = status.state;
+            // Only network stages are interrupted, later stages stop the job
+            // before the next one so no file is left half written. A job not
+            // spawned yet is aborted by `track_task_handle`.
+            let stopped = match status.abort_handle.take() {
+                Some(handle)
+                    if matches!(
+                        previous,
+                        TaskState::Queued | TaskState::FetchingMetadata | TaskState::Downloading
+                    ) =>
+                {
+                    handle.abort();
+                    true
+                }
+                Some(handle) => {
+                    status.abort_handle = Some(handle);
+                    false
+                }
+                None => true,
+            };
+            apply_state(status, TaskState::Cancelled);
+            (status.clone(), previous, stopped)
+        };
+        info!("Cancelled task {id} in state {previous}");
+
+        if let Err(e) = self.archive_db.lock().await.save_task(&Task::from(&status)) {
+            error!("Failed to record state of task {id}: {e:?}");
+        }
+        // A running job cleans up once it stops, in `finish_task`
+        if stopped {
+            self.cleanup_cancelled_task(&status).await;
+        }
+        self.send_event(TaskEvent::Cancelled { id: id.to_string() });
+
+        Ok(status)
+    }
+
+    /// Removes the partial download, the finished archive is kept.
+    async fn cleanup_cancelled_task(&self, status: &TaskStatus) {
+        let Some(gid_token) = status
+            .gid
+            .zip(status.token.as_ref())
+            .map(|(g, t)| format!("{g}_{t}"))
+        else {
+            return;
+        };
+        let gallery_dir = format!("{}/{}", self.output.display(), gid_token);
+        let part_path = format!("{gallery_dir}/{gid_token}.cbz.part");
+
+        if tokio::fs::remove_file(&part_path).await.is_ok() {
+            info!("Removed partial file: {part_path}");
+        }
+        // Only succeeds if nothing else is left in the gallery directory
+        let _ = tokio::fs::remove_dir(&gallery_dir).await;
+    }
+
+    fn send_event(&self, event: TaskEvent) {
+        // No subscribers is not an error
+        let _ = self.events.send(event);
+    }
+
+    pub(crate) async fn update_task<F: FnOnce(&mut TaskStatus)>(&self, id: &str, f: F) {
+        let task = {
+            let mut active_tasks = self.active_tasks.lock().await;
+            let Some(status) = active_tasks.get_mut(id) else {
+                warn!("Task {id} is not tracked");
+                return;
+            };
+            f(status);
+            Task::from(&*status)
+        };
+
+        if let Err(e) = self.archive_db.lock().await.save_task(&task) {
+            error!("Failed to record state of task {id}: {e:?}");
+        }
+    }
+
+    pub(crate) async fn with_retry<T, F, Fut>(
+        &self,
+        task_id: &str,
+        policy: RetryPolicy,
+        mut f: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < policy.max_attempts && is_retryable(&e) => {
+                    let delay = policy.delay(attempt);
+                    warn!(
+                        "Task {task_id} attempt {attempt}/{} failed, retrying in {delay:?}: {e}",
+                        policy.max_attempts
+                    );
+                    self.update_task(task_id, |status| {
+                        status.retries += 1;
+                        status.error = Some(e.to_string());
+                    })
+                    .await;
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn prune_tasks(&self) {
+        let since = Utc::now().timestamp() - self.failed_task_retention;
+        self.active_tasks
+            .lock()
+            .await
+            .retain(|_, t| t.finished_at.is_none_or(|finished_at| finished_at >= since));
+
+        match self
+            .archive_db
+            .lock()
+            .await
+            .delete_finished_tasks_before(since)
+        {
+            Ok(0) => {}
+            Ok(deleted) => info!("Pruned {deleted} finished tasks"),
+            Err(e) => error!("Failed to prune finished tasks: {e:?}"),
+        }
+    }
+
+    /// The calibre add is not idempotent, so a job that may have got as far
+    /// as adding its book must not add it again.
+    async fn find_added_book(&self, status: &TaskStatus, state: TaskState) -> Option<i32> {
+        let adds_book = match status.kind {
+            TaskKind::Import => true,
+            // A set book id means the job replaces the file of an existing book
+            TaskKind::Download => state == TaskState::AddingToCalibre && status.book_id.is_none(),
+            _ => false,
+        };
+        if !adds_book {
+            return None;
+        }
+        let identifier =
+            gallery_identifier(status.gid?, status.token.as_deref()?, self.is_exhentai);
+        match find_book_id(&self.calibre, &identifier).await {
+            Ok(book_id) => book_id,
+            Err(e) => {
+                warn!("Failed to look up {identifier} in calibre: {e}");
+                None
+            }
+        }
+    }
+
+    pub async fn resume_tasks(&self) {
+        self.prune_tasks().await;
+        let since = Utc::now().timestamp() - self.failed_task_retention;
+        let failed = match self.archive_db.lock().await.get_failed_tasks_since(since) {
+            Ok(tasks) => tasks,
+            Err(e) => {
+                error!("Failed to load failed tasks: {e:?}");
+                Vec::new()
+            }
+        };
+        {
+            let mut active_tasks = self.active_tasks.lock().await;
+            for task in failed {
+                if let Ok(status) = TaskStatus::try_from(task) {
+                    active_tasks.insert(status.id.clone(), status);
+                }
+            }
+        }
+
+        let tasks = match self.archive_db.lock().await.get_unfinished_tasks() {
+            Ok(tasks) => tasks,
+            Err(e) => {
+                error!("Failed to load unfinished tasks: {e:?}");
+                return;
+            }
+        };
+
+        if tasks.is_empty() {
+            return;
+        }
+        info!("Resuming {} unfinished tasks", tasks.len());
+
+        for task in tasks {
+            let id = task.id.clone();
+            let mut status = match TaskStatus::try_from(task) {
+                Ok(status) => status,
+                Err(msg) => {
+                    warn!("Cannot resume task {id}: {msg}");
+                    continue;
+                }
+            };
+            let state = status.state;
+            status.state = TaskState::Queued;
+            self.active_tasks
+                .lock()
+                .await
+                .insert(id.clone(), status.clone());
+
+            if let Some(book_id) = self.find_added_book(&status, state).await {
+                info!(
+                    "Book {book_id} was already added before the restart: {}",
+                    status.url
+                );
+                self.update_task(&id, |status| status.book_id = Some(book_id))
+                    .await;
+                self.finish_task(&id, &Ok(())).await;
+                continue;
+            }
+
+            let on_existing = status.on_existing.unwrap_or_default();
+            let resumable = match (status.kind, status.download_type, status.path) {
+                (TaskKind::Download | TaskKind::Upgrade, Some(download_type), _) => {
+                    info!("Resuming download: {}", status.url);
+                    self.spawn_download(id.clone(), status.url, download_type, on_existing, None)
+                        .await;
+                    Ok(())
+                }
+                (TaskKind::Download | TaskKind::Upgrade, None, _) => {
+                    Err(anyhow!("Missing download type"))
+                }
+                (TaskKind::Import, _, Some(path)) => match check_archive(&path) {
+                    Ok(_) => {
+                        info!("Resuming import: {}", status.url);
+                        self.spawn_import(id.clone(), status.url, path).await;
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                },
+                (TaskKind::Import, _, None) => Err(anyhow!("Missing archive path")),
+                (TaskKind::MetadataReplace, _, _) => {
+                    info!("Resuming book metadata replacement: {}", status.url);
+                    self.spawn_book_metadata_replace(id.clone(), status.url)
+                        .await;
+                    Ok(())
+                }
+                (TaskKind::MetadataUpdate, _, _) => {
+                    info!("Resuming metadata update");
+                    self.spawn_metadata_update(id.clone()).await;
+                    Ok(())
+                }
+                (TaskKind::MetadataRevert, _, _) => {
+                    info!("Resuming metadata revert");
+                    self.spawn_metadata_revert(id.clone()).await;
+                    Ok(())
+                }
+                (TaskKind::VersionCheck, upgrade, _) => {
+                    info!("Resuming version check");
+                    self.spawn_version_check(id.clone(), upgrade).await;
+                    Ok(())
+                }
+            };
+
+            if let Err(e) = resumable {
+                warn!("Cannot resume task {id}: {e}");
+                self.finish_task(&id, &Err(e)).await;
+            }
+        }
+    }
+}
+
+fn apply_state(status: &mut TaskStatus, state: TaskState) {
+    let now = Utc::now().timestamp();
+    if status.started_at.is_none() && state != TaskState::Queued {
+        status.started_at = Some(now);
+    }
+    if state.is_finished() {
+        status.finished_at = Some(now);
+    }
+    status.state = state;
+}
@@ -0,0 +1,429 @@
+use anyhow::{Result, anyhow};
+use libeh::client::auth::EhClientAuth;
+use log::warn;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::{
+    Client, Response, StatusCode, Url,
+    header::{self, HeaderMap, HeaderValue},
+};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+use super::retry::{PermanentError, TransientError};
+use crate::api::USER_AGENT;
+
+/// Progress is reported every step, or every percent if the size is known.
+const PROGRESS_STEP: u64 = 1 << 20;
+
+static ARCHIVE_URL_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(https?://[^"'\s<>]+/archive/[^"'\s<>]+)"#).unwrap());
+
+#[derive(Clone)]
+pub struct ArchiveClient {
+    client: Client,
+    base_url: String,
+}
+
+/// An archive response and the length of the `.part` file it continues.
+pub struct ArchiveDownload {
+    pub response: Response,
+    pub offset: u64,
+    /// Set when the archive URL was resolved instead of resumed.
+    pub resolved_url: Option<Url>,
+}
+
+impl ArchiveClient {
+    pub fn new(auth: &EhClientAuth, is_exhentai: bool) -> Result<Self> {
+        let mut cookie = format!(
+            "ipb_member_id={}; ipb_pass_hash={}",
+            auth.ipb_member_id, auth.ipb_pass_hash
+        );
+        if let Some(igneous) = &auth.igneous {
+            cookie.push_str(&format!("; igneous={igneous}"));
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_str(&cookie)?);
+
+        let client = Client::builder()
+            .user_agent(USER_AGENT)
+            .default_headers(headers)
+            .build()?;
+
+        let host = if is_exhentai {
+            "exhentai.org"
+        } else {
+            "e-hentai.org"
+        };
+        Ok(Self {
+            client,
+            base_url: format!("https://{host}"),
+        })
+    }
+
+    pub async fn resolve_archive_url(
+        &self,
+        gid: i64,
+        token: &str,
+        is_original: bool,
+    ) -> Result<Url> {
+        let archiver_url = format!("{}/archiver.php?gid={gid}&token={token}", self.base_url);
+        let (dltype, dlcheck) = if is_original {
+            ("org", "Download Original Archive")
+        } else {
+            ("res", "Download Resample Archive")
+        };
+
+        let html = self
+            .client
+            .post(&archiver_url)
+            .form(&[("dltype", dltype), ("dlcheck", dlcheck)])
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        if html.contains("Insufficient funds") || html.contains("do not have enough") {
+            return Err(
+                PermanentError("Insufficient funds to download the archive".to_string()).into(),
+            );
+        }
+
+        let url = ARCHIVE_URL_REGEX
+            .captures(&html)
+            .and_then(|c| c.get(1))
+            .ok_or_else(|| anyhow!("Archive download URL not found in archiver response"))?;
+        let mut url = Url::parse(url.as_str())?;
+        url.set_query(Some("start=1"));
+
+        Ok(url)
+    }
+
+    pub async fn get(&self, url: Url, offset: u64) -> Result<Response> {
+        let mut request = self.client.get(url);
+        if offset > 0 {
+            request = request.header(header::RANGE, format!("bytes={offset}-"));
+        }
+        let response = request.send().await?;
+        Ok(response)
+    }
+
+    /// Continues a partial download from the saved archive URL, or resolves
+    /// the archive URL again and starts over if the server does not resume it.
+    pub async fn open(
+        &self,
+        gid: i64,
+        token: &str,
+        is_original: bool,
+        saved_url: Option<&str>,
+        offset: u64,
+    ) -> Result<ArchiveDownload> {
+        let resumed = match saved_url {
+            Some(archive_url) if offset > 0 => self.resume(archive_url, offset).await,
+            _ => None,
+        };
+        if let Some(response) = resumed {
+            return Ok(ArchiveDownload {
+                response,
+                offset,
+                resolved_url: None,
+            });
+        }
+
+        let archive_url = self.resolve_archive_url(gid, token, is_original).await?;
+        let response = self.get(archive_url.clone(), 0).await?.error_for_status()?;
+        if !is_archive_response(&response) {
+            return Err(
+                TransientError("Archive server did not return an archive".to_string()).into(),
+            );
+        }
+
+        Ok(ArchiveDownload {
+            response,
+            offset: 0,
+            resolved_url: Some(archive_url),
+        })
+    }
+
+    async fn resume(&self, archive_url: &str, offset: u64) -> Option<Response> {
+        let archive_url = Url::parse(archive_url).ok()?;
+        match self.get(archive_url, offset).await {
+            Ok(response) if is_resumed_response(&response, offset) => Some(response),
+            Ok(response) => {
+                warn!(
+                    "Archive server rejected range request (status {}), starting over",
+                    response.status()
+                );
+                None
+            }
+            Err(e) => {
+                warn!("Failed to resume archive download, starting over: {e}");
+                None
+            }
+        }
+    }
+}
+
+/// Appends the download to the `.part` file, or truncates it when starting
+/// over, and returns the size of the archive.
+pub async fn write_archive<F, Fut>(
+    download: ArchiveDownload,
+    expected_size: Option<u64>,
+    part_path: &str,
+    mut on_progress: F,
+) -> Result<u64>
+where
+    F: FnMut(u64, Option<u64>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let ArchiveDownload {
+        mut response,
+        offset,
+        ..
+    } = download;
+    let content_length = response.content_length().map(|length| length + offset);
+    let total = content_length.or(expected_size);
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(offset > 0)
+        .truncate(offset == 0)
+        .open(part_path)
+        .await?;
+    let step = total.map_or(PROGRESS_STEP, |total| (total / 100).max(1));
+    let mut received = offset;
+    let mut reported = received;
+    on_progress(received, total).await;
+    while let Some(chunk) = response.chunk().await? {
+        file.write_all(&chunk).await?;
+        received += chunk.len() as u64;
+        if received / step != reported / step {
+            on_progress(received, total).await;
+            reported = received;
+        }
+    }
+    if received != reported {
+        on_progress(received, total).await;
+    }
+    file.sync_all().await?;
+
+    if content_length.is_some_and(|length| received != length) {
+        return Err(TransientError(format!(
+            "Archive download incomplete: received {} of {} bytes",
+            received,
+            content_length.unwrap_or_default()
+        ))
+        .into());
+    }
+
+    Ok(received)
+}
+
+fn content_range_start(response: &Response) -> Option<u64> {
+    let value = response
+        .headers()
+        .get(header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?;
+    let range = value.strip_prefix("bytes ")?;
+    let (start, _) = range.split_once('-')?;
+    start.trim().parse().ok()
+}
+
+fn is_resumed_response(response: &Response, offset: u64) -> bool {
+    response.status() == StatusCode::PARTIAL_CONTENT
+        && content_range_start(response) == Some(offset)
+        && is_archive_response(response)
+}
+
+fn is_archive_response(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_none_or(|v| !v.starts_with("text/html"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Write},
+        path::{Path as FsPath, PathBuf},
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    use axum::{
+        Router,
+        extract::{Path, State},
+        http::{HeaderMap, StatusCode, header},
+        response::{Html, IntoResponse, Response},
+        routing::{get, post},
+    };
+    use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+    use super::*;
+    use crate::api::utils::validate_archive;
+
+    #[derive(Clone)]
+    struct Server {
+        base_url: String,
+        archive: Arc<Vec<u8>>,
+        resolved: Arc<AtomicUsize>,
+    }
+
+    fn zip_archive() -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        for i in 0..4 {
+            zip.start_file(format!("{i:03}.jpg"), options).unwrap();
+            zip.write_all(&[i as u8; 4096]).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn range_start(headers: &HeaderMap) -> Option<usize> {
+        let range = headers.get(header::RANGE)?.to_str().ok()?;
+        range
+            .strip_prefix("bytes=")?
+            .strip_suffix('-')?
+            .parse()
+            .ok()
+    }
+
+    /// Serves the archive under a few behaviours an archive server shows.
+    async fn archive(
+        State(server): State<Server>,
+        Path(kind): Path<String>,
+        headers: HeaderMap,
+    ) -> Response {
+        let archive = server.archive.as_slice();
+        let len = archive.len();
+        match (kind.as_str(), range_start(&headers)) {
+            ("ranged", Some(start)) | ("wrong_start", Some(start)) => {
+                let reported = if kind == "wrong_start" { 0 } else { start };
+                (
+                    StatusCode::PARTIAL_CONTENT,
+                    [(
+                        header::CONTENT_RANGE,
+                        format!("bytes {reported}-{}/{len}", len - 1),
+                    )],
+                    archive[start..].to_vec(),
+                )
+                    .into_response()
+            }
+            ("expired", _) => (StatusCode::GONE, Html("Link expired")).into_response(),
+            _ => archive.to_vec().into_response(),
+        }
+    }
+
+    async fn archiver(State(server): State<Server>) -> Html<String> {
+        server.resolved.fetch_add(1, Ordering::SeqCst);
+        Html(format!(
+            r#"<a href="{}/archive/fresh">Click here</a>"#,
+            server.base_url
+        ))
+    }
+
+    async fn start_server() -> (ArchiveClient, Server) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = Server {
+            base_url: format!("http://{}", listener.local_addr().unwrap()),
+            archive: Arc::new(zip_archive()),
+            resolved: Arc::new(AtomicUsize::new(0)),
+        };
+        let app = Router::new()
+            .route("/archiver.php", post(archiver))
+            .route("/archive/{kind}", get(archive))
+            .with_state(server.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let client = ArchiveClient {
+            client: Client::new(),
+            base_url: server.base_url.clone(),
+        };
+        (client, server)
+    }
+
+    /// Leaves the first `len` bytes of the archive in a fresh `.part` file.
+    async fn part_file(server: &Server, len: usize) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}.cbz.part", uuid::Uuid::new_v4()));
+        tokio::fs::write(&path, &server.archive[..len])
+            .await
+            .unwrap();
+        path
+    }
+
+    async fn download(
+        client: &ArchiveClient,
+        saved_url: &str,
+        part_path: &FsPath,
+    ) -> ArchiveDownload {
+        let offset = tokio::fs::metadata(part_path).await.unwrap().len();
+        client
+            .open(1, "token", true, Some(saved_url), offset)
+            .await
+            .unwrap()
+    }
+
+    async fn finish(download: ArchiveDownload, part_path: &FsPath, server: &Server) {
+        let part = part_path.to_str().unwrap();
+        let size = write_archive(download, None, part, |_, _| async {})
+            .await
+            .unwrap();
+        assert_eq!(size, server.archive.len() as u64);
+        assert_eq!(tokio::fs::read(part).await.unwrap(), *server.archive);
+        validate_archive(part).unwrap();
+        tokio::fs::remove_file(part).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn appends_partial_content() {
+        let (client, server) = start_server().await;
+        let part_path = part_file(&server, 1000).await;
+
+        let saved_url = format!("{}/archive/ranged", server.base_url);
+        let download = download(&client, &saved_url, &part_path).await;
+        assert_eq!(download.offset, 1000);
+        assert!(download.resolved_url.is_none());
+
+        finish(download, &part_path, &server).await;
+        assert_eq!(server.resolved.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn starts_over_without_partial_content() {
+        let (client, server) = start_server().await;
+
+        for kind in ["full", "wrong_start"] {
+            let part_path = part_file(&server, 1000).await;
+            let saved_url = format!("{}/archive/{kind}", server.base_url);
+            let download = download(&client, &saved_url, &part_path).await;
+            assert_eq!(download.offset, 0);
+
+            finish(download, &part_path, &server).await;
+        }
+        assert_eq!(server.resolved.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn resolves_expired_archive_url() {
+        let (client, server) = start_server().await;
+        let part_path = part_file(&server, 1000).await;
+
+        let saved_url = format!("{}/archive/expired", server.base_url);
+        let download = download(&client, &saved_url, &part_path).await;
+        assert_eq!(download.offset, 0);
+        assert_eq!(
+            download.resolved_url.as_ref().map(Url::path),
+            Some("/archive/fresh")
+        );
+
+        finish(download, &part_path, &server).await;
+        assert_eq!(server.resolved.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,9 +1,12 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use anyhow::{Result, anyhow};
+use chrono::Utc;
 use libcalibre::{
     UpsertBookIdentifier,
-    client::CalibreClient,
     dtos::{
         author::NewAuthorDto,
         book::NewBookDto,
@@ -14,29 +17,29 @@ use libcalibre::{
         tag::NewTagDto,
     },
 };
-use libeh::{
-    client::client::EhClient,
-    dto::{
-        api::{GIDListItem, GalleryMetadata, GalleryMetadataRequest, GalleryMetadataResponse},
-        keyword::Keyword,
-    },
-};
+use libeh::{client::client::EhClient, dto::api::GalleryMetadata};
 use log::info;
-use once_cell::sync::Lazy;
-use regex::Regex;
-use reqwest::Url;
 use tokio::sync::Mutex;
 
-use super::{parse_category, parse_tag};
+use super::{
+    fetch_gallery_metadata,
+    mapping::{CalibreField, MappingProfile},
+    parse_category, parse_gallery_url, parse_tag, posted_time, save_jpeg_cover,
+    title::{parse_series, parse_title},
+};
+use crate::archive_db::{DisplayMode, TranslationKind, db::ArchiveDb};
+use crate::calibre_db::{Calibre, ColumnValue, db::CalibreDb};
+use crate::g_info;
 use crate::tag_db::db::EhTagDb;
-use crate::{api::EH_API_URL, g_info};
 
-static TITLE_REGEX: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"(?:\([^\(\)]+\))?\s*(?:\[[^\[\]]+\])?\s*([^\[\]\(\)]+)").unwrap());
+/// Raw names a book shows translated, recorded to revert them per book.
+type TranslationLinks = Vec<(TranslationKind, String)>;
 
 async fn gallery_to_dto(
     tag_db: Arc<Mutex<EhTagDb>>,
+    archive_db: Arc<Mutex<ArchiveDb>>,
     is_exhentai: bool,
+    mapping: &MappingProfile,
     cbz_path: Option<String>,
     metadata: GalleryMetadata,
 ) -> Result<(
@@ -48,7 +51,10 @@ async fn gallery_to_dto(
     Vec<UpsertBookIdentifier>,
     Option<NewRatingDto>,
     Option<Vec<NewLibraryFileDto>>,
+    Option<(String, f32)>,
+    TranslationLinks,
 )> {
+    let pubdate = posted_time(&metadata).map(|t| t.naive_utc());
     let gallery_title = metadata.title;
     let gallery_title_jpn = metadata.title_jpn;
     let gallery_category = parse_category(metadata.category);
@@ -57,21 +63,19 @@ async fn gallery_to_dto(
     let gallery_rating = metadata.rating;
     let gallery_tags = metadata.tags;
 
-    let title = if !gallery_title_jpn.is_empty() {
-        &gallery_title_jpn
+    let title = mapping
+        .title_form
+        .select(&gallery_title, &gallery_title_jpn);
+    let series = if mapping.detect_series {
+        parse_series(&parse_title(&title).title)
     } else {
-        &gallery_title
+        None
     };
-    let title = TITLE_REGEX
-        .captures(title)
-        .and_then(|c| c.get(1))
-        .map(|m| m.as_str().trim().to_string())
-        .unwrap_or(gallery_title);
     let book_dto = NewBookDto {
         title,
         timestamp: None,
-        pubdate: None,
-        series_index: 1.0,
+        pubdate,
+        series_index: series.as_ref().map_or(1.0, |(_, index)| *index),
         flags: 1,
         has_cover: None,
     };
@@ -91,12 +95,7 @@ async fn gallery_to_dto(
         book_id: 0,
         id: None,
         label: "ehentai".to_string(),
-        value: format!(
-            "{}_{}_{}",
-            gallery_gid,
-            gallery_token,
-            if is_exhentai { 1 } else { 0 }
-        ),
+        value: gallery_identifier(gallery_gid, &gallery_token, is_exhentai),
     }];
 
     let rating_dto = Some(NewRatingDto {
@@ -109,13 +108,21 @@ async fn gallery_to_dto(
     let mut publishers_dto: Vec<NewPublisherDto> = Vec::new();
     let mut language_dto: Option<NewLanguageDto> = None;
     let mut tags_dto: Vec<NewTagDto> = Vec::new();
+    let mut links = Vec::new();
+
+    let display_mode = archive_db.lock().await.get_display_mode()?;
 
     for tag in gallery_tags {
-        let result = parse_tag(&tag);
+        let result = parse_tag(&tag, &mapping.tag_prefixes);
         if result.is_none() {
             continue;
         }
         let (namespace, raw_tag) = result.unwrap();
+        let fields = mapping.fields(namespace);
+        if fields.is_empty() {
+            continue;
+        }
+        let raw_tag = tag_db.lock().await.resolve_alias(namespace, raw_tag)?;
         let tag_namespace = tag_db
             .lock()
             .await
@@ -124,71 +131,90 @@ async fn gallery_to_dto(
         let tag_name = tag_db
             .lock()
             .await
-            .get_tag_name(namespace, raw_tag)?
-            .unwrap_or_else(|| raw_tag.to_string());
-        match tag {
-            Keyword::Artist(_) => {
-                let author_dto = NewAuthorDto {
-                    full_name: tag_name,
-                    sortable_name: String::new(),
-                    external_url: None,
-                };
-                authors_dto.push(author_dto);
-            }
-            Keyword::Group(_) => {
-                let publisher_dto = NewPublisherDto {
-                    name: tag_name,
-                    sort: None,
-                };
-                publishers_dto.push(publisher_dto);
-            }
-            Keyword::Language(_) => {
-                language_dto = Some(NewLanguageDto {
-                    lang_code: raw_tag.to_string(),
-                });
-                let tag_dto = NewTagDto {
-                    name: format!("{tag_namespace}:{tag_name}"),
-                };
-                tags_dto.push(tag_dto);
-            }
-            _ => {
-                let tag_dto = NewTagDto {
-                    name: format!("{tag_namespace}:{tag_name}"),
-                };
-                tags_dto.push(tag_dto);
+            .get_tag_name(namespace, &raw_tag)?
+            .unwrap_or_else(|| raw_tag.clone());
+        for field in fields {
+            match field {
+                CalibreField::Authors => {
+                    let author_dto = NewAuthorDto {
+                        full_name: display_name(
+                            &archive_db,
+                            display_mode,
+                            mapping,
+                            TranslationKind::Author,
+                            &raw_tag,
+                            &tag_name,
+                            &mut links,
+                        )
+                        .await?,
+                        sortable_name: String::new(),
+                        external_url: None,
+                    };
+                    authors_dto.push(author_dto);
+                }
+                CalibreField::Publishers => {
+                    let publisher_dto = NewPublisherDto {
+                        name: display_name(
+                            &archive_db,
+                            display_mode,
+                            mapping,
+                            TranslationKind::Publisher,
+                            &raw_tag,
+                            &tag_name,
+                            &mut links,
+                        )
+                        .await?,
+                        sort: None,
+                    };
+                    publishers_dto.push(publisher_dto);
+                }
+                CalibreField::Language => {
+                    language_dto = Some(NewLanguageDto {
+                        lang_code: raw_tag.clone(),
+                    });
+                }
+                CalibreField::Tags => {
+                    let tag_dto = NewTagDto {
+                        name: display_name(
+                            &archive_db,
+                            display_mode,
+                            mapping,
+                            TranslationKind::Tag,
+                            &mapping.format_tag(namespace, &raw_tag),
+                            &mapping.format_tag(&tag_namespace, &tag_name),
+                            &mut links,
+                        )
+                        .await?,
+                    };
+                    tags_dto.push(tag_dto);
+                }
             }
         }
     }
 
-    let authors_dto = if authors_dto.is_empty() {
-        vec![NewAuthorDto {
-            full_name: "Unknown".to_string(),
+    if authors_dto.is_empty() && !mapping.unknown_author.is_empty() {
+        authors_dto.push(NewAuthorDto {
+            full_name: mapping.unknown_author.clone(),
             sortable_name: String::new(),
             external_url: None,
-        }]
-    } else {
-        authors_dto
-    };
+        });
+    }
 
-    let publishers_dto = if publishers_dto.is_empty() {
-        vec![NewPublisherDto {
-            name: "Unknown".to_string(),
+    if publishers_dto.is_empty() && !mapping.unknown_publisher.is_empty() {
+        publishers_dto.push(NewPublisherDto {
+            name: mapping.unknown_publisher.clone(),
             sort: None,
-        }]
-    } else {
-        publishers_dto
-    };
+        });
+    }
 
     let language_dto = language_dto.or_else(|| {
-        Some(NewLanguageDto {
-            lang_code: "jpn".to_string(),
+        (!mapping.default_language.is_empty()).then(|| NewLanguageDto {
+            lang_code: mapping.default_language.clone(),
         })
     });
 
-    if category_name.is_some() {
-        let tag_dto = NewTagDto {
-            name: format!("分类:{}", category_name.unwrap()),
-        };
+    if let Some(name) = category_name.and_then(|c| mapping.format_category(&c)) {
+        let tag_dto = NewTagDto { name };
         tags_dto.push(tag_dto);
     }
 
@@ -201,19 +227,187 @@ async fn gallery_to_dto(
         identifiers_dto,
         rating_dto,
         files_dto,
+        series,
+        links,
     ))
 }
 
+/// Records the translation of a raw name so it can be reverted, and returns
+/// the name to show in calibre.
+async fn display_name(
+    archive_db: &Mutex<ArchiveDb>,
+    display_mode: DisplayMode,
+    mapping: &MappingProfile,
+    kind: TranslationKind,
+    raw: &str,
+    translated: &str,
+    links: &mut TranslationLinks,
+) -> Result<String> {
+    if raw != translated {
+        archive_db
+            .lock()
+            .await
+            .save_translation(kind, raw, translated)?;
+        links.push((kind, raw.to_string()));
+    }
+    Ok(display_mode.render(kind, raw, translated, mapping))
+}
+
+pub fn gallery_identifier(gid: i64, token: &str, is_exhentai: bool) -> String {
+    format!("{}_{}_{}", gid, token, if is_exhentai { 1 } else { 0 })
+}
+
+pub async fn find_book_id(calibre: &Mutex<Calibre>, identifier: &str) -> Result<Option<i32>> {
+    calibre
+        .lock()
+        .await
+        .client
+        .find_book_id_by_identifier("ehentai", identifier)
+        .map_err(|e| anyhow!("{}", e))
+}
+
+/// Looks up or creates the custom columns of the mapping profile.
+pub fn ensure_custom_columns(calibre_db: &mut CalibreDb, mapping: &MappingProfile) -> Result<()> {
+    for (field, column) in &mapping.custom_columns {
+        let datatype = column.datatype.unwrap_or(field.column_type());
+        let name = column.name.as_deref().unwrap_or(field.display_name());
+        let datatype = calibre_db.ensure_custom_column(&column.label, name, datatype)?;
+        if !datatype.accepts(field.column_type()) {
+            return Err(anyhow!(
+                "Custom column #{} is {}, which cannot hold {:?}",
+                column.label,
+                datatype.as_str(),
+                field
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn write_series(
+    calibre_db: &mut CalibreDb,
+    book_id: i32,
+    series: Option<(String, f32)>,
+    gid_token: &str,
+) -> Result<()> {
+    let Some((name, index)) = series else {
+        return Ok(());
+    };
+    g_info!(
+        gid_token,
+        "Adding book {book_id} to series {name} at {index}"
+    );
+    calibre_db.set_book_series(book_id, &name, index)
+}
+
+/// Wraps the generated part of the book comments, so it can be replaced
+/// without touching what the user wrote.
+const TITLE_COMMENTS_START: &str = "<div class=\"eharchive-titles\">";
+const TITLE_COMMENTS_END: &str = "</div>";
+
+/// The full titles and their parsed parts, as HTML for the book comments.
+fn title_comments(metadata: &GalleryMetadata) -> Option<String> {
+    let mut lines = Vec::new();
+    let mut push = |label: &str, value: Option<&str>| {
+        if let Some(value) = value.filter(|v| !v.is_empty()) {
+            lines.push(format!("{label}: {}", escape_html(value)));
+        }
+    };
+    push("日文标题", Some(&metadata.title_jpn));
+    push("英文标题", Some(&metadata.title));
+
+    let parsed = parse_title(if metadata.title_jpn.is_empty() {
+        &metadata.title
+    } else {
+        &metadata.title_jpn
+    });
+    push("展会", parsed.event.as_deref());
+    push("社团", parsed.circle.as_deref());
+    push("作者", parsed.artist.as_deref());
+    push("原作", parsed.parody.as_deref());
+    push("语言", parsed.language.as_deref());
+    push("翻译", parsed.translator.as_deref());
+    if parsed.dl {
+        lines.push("DL版".to_string());
+    }
+
+    (!lines.is_empty()).then(|| {
+        format!(
+            "{TITLE_COMMENTS_START}<p>{}</p>{TITLE_COMMENTS_END}",
+            lines.join("<br>")
+        )
+    })
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn write_comments(
+    calibre_db: &mut CalibreDb,
+    book_id: i32,
+    comments: Option<String>,
+) -> Result<()> {
+    let Some(comments) = comments else {
+        return Ok(());
+    };
+    let existing = calibre_db.get_book_comments(book_id)?.unwrap_or_default();
+    let block = existing.find(TITLE_COMMENTS_START).and_then(|start| {
+        let end = existing[start..].find(TITLE_COMMENTS_END)? + start + TITLE_COMMENTS_END.len();
+        Some(start..end)
+    });
+    let comments = match block {
+        Some(block) => {
+            let mut merged = existing;
+            merged.replace_range(block, &comments);
+            merged
+        }
+        None if existing.trim().is_empty() => comments,
+        // Comments written by the user stay in front
+        None => format!("{existing}{comments}"),
+    };
+    calibre_db.set_book_comments(book_id, &comments)
+}
+
+fn write_custom_columns(
+    calibre_db: &mut CalibreDb,
+    book_id: i32,
+    values: Vec<(String, ColumnValue)>,
+    gid_token: &str,
+) -> Result<()> {
+    if values.is_empty() {
+        return Ok(());
+    }
+    g_info!(gid_token, "Writing custom columns of book {book_id}");
+    for (label, value) in values {
+        calibre_db.set_custom_column(book_id, &label, value)?;
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn add_to_calibre(
-    calibre_client: Arc<Mutex<CalibreClient>>,
+    calibre: Arc<Mutex<Calibre>>,
     tag_db: Arc<Mutex<EhTagDb>>,
+    archive_db: Arc<Mutex<ArchiveDb>>,
     is_exhentai: bool,
+    mapping: &MappingProfile,
     cbz_path: String,
     metadata: GalleryMetadata,
     gid_token: &str,
-) -> Result<()> {
+) -> Result<Option<i32>> {
+    let custom_columns = mapping.custom_column_values(&metadata);
+    let comments = if mapping.title_comments {
+        title_comments(&metadata)
+    } else {
+        None
+    };
     let (
-        book_dto,
+        mut book_dto,
         authors_dto,
         publishers_dto,
         language_dto,
@@ -221,7 +415,20 @@ pub async fn add_to_calibre(
         identifiers_dto,
         rating_dto,
         files_dto,
-    ) = gallery_to_dto(tag_db, is_exhentai, Some(cbz_path), metadata).await?;
+        series,
+        links,
+    ) = gallery_to_dto(
+        tag_db,
+        archive_db.clone(),
+        is_exhentai,
+        mapping,
+        Some(cbz_path),
+        metadata,
+    )
+    .await?;
+    // Added right after the download finishes
+    book_dto.timestamp = Some(Utc::now().naive_utc());
+    let identifier = identifiers_dto[0].value.clone();
     let dto = NewLibraryEntryDto {
         book: book_dto,
         authors: authors_dto,
@@ -234,58 +441,166 @@ pub async fn add_to_calibre(
     };
 
     g_info!(gid_token, "Adding book to calibre");
-    calibre_client
-        .lock()
-        .await
-        .add_book(dto)
+    let mut calibre = calibre.lock().await;
+    calibre.client.add_book(dto).map_err(|e| anyhow!("{}", e))?;
+    let book_id = calibre
+        .client
+        .find_book_id_by_identifier("ehentai", &identifier)
         .map_err(|e| anyhow!("{}", e))?;
+    if let Some(book_id) = book_id {
+        write_series(&mut calibre.db, book_id, series, gid_token)?;
+        write_comments(&mut calibre.db, book_id, comments)?;
+        write_custom_columns(&mut calibre.db, book_id, custom_columns, gid_token)?;
+        archive_db
+            .lock()
+            .await
+            .set_book_translations(book_id, &links)?;
+    }
 
-    Ok(())
+    Ok(book_id)
 }
 
 #[allow(clippy::cognitive_complexity)]
 pub async fn update_metadata(
-    calibre_client: Arc<Mutex<CalibreClient>>,
+    calibre: Arc<Mutex<Calibre>>,
     tag_db: Arc<Mutex<EhTagDb>>,
+    archive_db: Arc<Mutex<ArchiveDb>>,
+    mapping: &MappingProfile,
 ) -> Result<()> {
     let tags_in_tag_db = tag_db.lock().await.get_all_tags()?;
-    let rows_in_tag_db = tags_in_tag_db.get("rows").unwrap();
+    // Tags can only be matched with namespace translations, the rest still can
+    let no_rows = HashMap::new();
+    let rows_in_tag_db = tags_in_tag_db.get("rows").unwrap_or_else(|| {
+        log::warn!("Tag database has no namespace translations");
+        &no_rows
+    });
+    let aliases = tag_db.lock().await.get_aliases()?;
+    let display_mode = archive_db.lock().await.get_display_mode()?;
+    let tag_namespaces = tag_db.lock().await.tag_namespaces();
+    let author_namespaces = mapping.namespaces(
+        CalibreField::Authors,
+        tag_namespaces.iter().map(String::as_str),
+    );
+    let publisher_namespaces = mapping.namespaces(
+        CalibreField::Publishers,
+        tag_namespaces.iter().map(String::as_str),
+    );
+    let tag_field_namespaces = mapping.namespaces(
+        CalibreField::Tags,
+        tag_namespaces.iter().map(String::as_str),
+    );
 
-    let authors_in_calibre = calibre_client
+    let translate_name = |namespaces: &[&str], raw: &str| {
+        namespaces
+            .iter()
+            .find_map(|namespace| find_translation(&tags_in_tag_db, &aliases, namespace, raw))
+            .cloned()
+    };
+    let translate_tag = |raw: &str| {
+        if !mapping.prefix_namespaces {
+            // Without the prefix a tag is only its name, which has to have a
+            // single translation across the namespaces
+            let translations: HashSet<_> = tag_field_namespaces
+                .iter()
+                .filter_map(|namespace| find_translation(&tags_in_tag_db, &aliases, namespace, raw))
+                .collect();
+            if translations.len() > 1 {
+                log::warn!("Tag {raw} has translations in several namespaces, skipped");
+            }
+            let mut translations = translations.into_iter();
+            return match (translations.next(), translations.next()) {
+                (Some(translation), None) => Some(translation.clone()),
+                _ => None,
+            };
+        }
+        let (namespace, raw_tag) = mapping.parse_tag(raw)?;
+        let tag_namespace = rows_in_tag_db.get(namespace)?;
+        let tag_name = find_translation(&tags_in_tag_db, &aliases, namespace, raw_tag)?;
+        Some(mapping.format_tag(tag_namespace, tag_name))
+    };
+
+    // Translated names only change if their translation has changed since,
+    // e.g. by an override
+    let author_changes = changed_translations(
+        &archive_db,
+        display_mode,
+        mapping,
+        TranslationKind::Author,
+        |raw| translate_name(&author_namespaces, raw),
+    )
+    .await?;
+    let publisher_changes = changed_translations(
+        &archive_db,
+        display_mode,
+        mapping,
+        TranslationKind::Publisher,
+        |raw| translate_name(&publisher_namespaces, raw),
+    )
+    .await?;
+    let tag_changes = changed_translations(
+        &archive_db,
+        display_mode,
+        mapping,
+        TranslationKind::Tag,
+        translate_tag,
+    )
+    .await?;
+
+    let authors_in_calibre = calibre
         .lock()
         .await
+        .client
         .get_all_authors()
         .map_err(|e| anyhow!("{}", e))?;
-    let publishers_in_calibre = calibre_client
+    let publishers_in_calibre = calibre
         .lock()
         .await
+        .client
         .get_all_publishers()
         .map_err(|e| anyhow!("{}", e))?;
-    let tags_in_calibre = calibre_client
+    let tags_in_calibre = calibre
         .lock()
         .await
+        .client
         .get_all_tags()
         .map_err(|e| anyhow!("{}", e))?;
 
     let mut updated_count = 0;
 
     for author in authors_in_calibre {
-        let namespace = "artist";
-        let raw_tag = &author.name;
-        let author_name = tags_in_tag_db.get(namespace).unwrap().get(raw_tag);
-        if author_name.is_none() {
-            continue;
-        }
-        let author_name = author_name.unwrap();
-        if author_name == raw_tag {
+        let name = &author.name;
+        let translation = translate_name(&author_namespaces, name).filter(|t| t != name);
+        let is_raw = translation.is_some();
+        let Some(author_name) = retranslate(
+            &archive_db,
+            display_mode,
+            mapping,
+            TranslationKind::Author,
+            name,
+            translation,
+            author_changes.get(name),
+        )
+        .await?
+        else {
             continue;
+        };
+        if is_raw {
+            record_linked_books(
+                &calibre,
+                &archive_db,
+                TranslationKind::Author,
+                author.id,
+                name,
+            )
+            .await?;
         }
-        calibre_client
+        calibre
             .lock()
             .await
-            .replace_author_with_translation(author.id, author_name)
+            .client
+            .replace_author_with_translation(author.id, &author_name)
             .map_err(|e| anyhow!("{}", e))?;
-        log::info!("Replaced author {raw_tag} with translation {author_name}");
+        log::info!("Replaced author {name} with translation {author_name}");
         updated_count += 1;
     }
 
@@ -298,22 +613,39 @@ pub async fn update_metadata(
     updated_count = 0;
 
     for publisher in publishers_in_calibre {
-        let namespace = "group";
-        let raw_tag = &publisher.name;
-        let publisher_name = tags_in_tag_db.get(namespace).unwrap().get(raw_tag);
-        if publisher_name.is_none() {
-            continue;
-        }
-        let publisher_name = publisher_name.unwrap();
-        if publisher_name == raw_tag {
+        let name = &publisher.name;
+        let translation = translate_name(&publisher_namespaces, name).filter(|t| t != name);
+        let is_raw = translation.is_some();
+        let Some(publisher_name) = retranslate(
+            &archive_db,
+            display_mode,
+            mapping,
+            TranslationKind::Publisher,
+            name,
+            translation,
+            publisher_changes.get(name),
+        )
+        .await?
+        else {
             continue;
+        };
+        if is_raw {
+            record_linked_books(
+                &calibre,
+                &archive_db,
+                TranslationKind::Publisher,
+                publisher.id,
+                name,
+            )
+            .await?;
         }
-        calibre_client
+        calibre
             .lock()
             .await
-            .replace_publisher_with_translation(publisher.id, publisher_name)
+            .client
+            .replace_publisher_with_translation(publisher.id, &publisher_name)
             .map_err(|e| anyhow!("{}", e))?;
-        log::info!("Replaced publisher {raw_tag} with translation {publisher_name}");
+        log::info!("Replaced publisher {name} with translation {publisher_name}");
         updated_count += 1;
     }
 
@@ -326,33 +658,31 @@ pub async fn update_metadata(
     updated_count = 0;
 
     for tag in tags_in_calibre {
-        let parts: Vec<_> = tag.name.split(':').collect();
-
-        if parts.len() != 2 {
-            continue;
-        }
-
-        let namespace = parts[0];
-        let raw_tag = parts[1];
-
-        if let (Some(tags_map), Some(tag_namespace)) =
-            (tags_in_tag_db.get(namespace), rows_in_tag_db.get(namespace))
+        let name = &tag.name;
+        let translation = translate_tag(name).filter(|t| t != name);
+        let is_raw = translation.is_some();
+        if let Some(translation) = retranslate(
+            &archive_db,
+            display_mode,
+            mapping,
+            TranslationKind::Tag,
+            name,
+            translation,
+            tag_changes.get(name),
+        )
+        .await?
         {
-            let tag_name = tags_map.get(raw_tag);
-            if tag_name.is_none() {
-                continue;
+            if is_raw {
+                record_linked_books(&calibre, &archive_db, TranslationKind::Tag, tag.id, name)
+                    .await?;
             }
-            let tag_name = tag_name.unwrap();
-            if tag_name == raw_tag {
-                continue;
-            }
-            let translation = format!("{tag_namespace}:{tag_name}");
-            calibre_client
+            calibre
                 .lock()
                 .await
+                .client
                 .replace_tag_with_translation(tag.id, &translation)
                 .map_err(|e| anyhow!("{}", e))?;
-            log::info!("Replaced tag {} with translation {}", tag.name, translation);
+            log::info!("Replaced tag {name} with translation {translation}");
             updated_count += 1;
         }
     }
@@ -366,57 +696,302 @@ pub async fn update_metadata(
     Ok(())
 }
 
-static URL_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"/g/(\d+)/([a-f0-9]+)/?").unwrap());
+/// Records the books linked to a raw calibre name about to be translated.
+async fn record_linked_books(
+    calibre: &Mutex<Calibre>,
+    archive_db: &Mutex<ArchiveDb>,
+    kind: TranslationKind,
+    id: i32,
+    raw: &str,
+) -> Result<()> {
+    let book_ids = calibre.lock().await.db.get_linked_books(kind, id)?;
+    archive_db
+        .lock()
+        .await
+        .add_translation_books(kind, raw, &book_ids)
+}
+
+/// Looks a raw calibre name up in the tag database. Aliases translate like
+/// their canonical tag, or are replaced by it if it has no translation.
+fn find_translation<'a>(
+    tags_in_tag_db: &'a HashMap<String, HashMap<String, String>>,
+    aliases: &'a HashMap<String, HashMap<String, String>>,
+    namespace: &str,
+    name: &str,
+) -> Option<&'a String> {
+    let canonical = aliases.get(namespace).and_then(|names| names.get(name));
+    tags_in_tag_db
+        .get(namespace)
+        .and_then(|tags_map| tags_map.get(canonical.map_or(name, String::as_str)))
+        .or(canonical)
+}
+
+/// Maps the calibre name of each recorded translation that no longer matches
+/// the tag database to its raw name and current translation.
+async fn changed_translations(
+    archive_db: &Mutex<ArchiveDb>,
+    display_mode: DisplayMode,
+    mapping: &MappingProfile,
+    kind: TranslationKind,
+    translate: impl Fn(&str) -> Option<String>,
+) -> Result<HashMap<String, (String, String)>> {
+    let translations = archive_db.lock().await.get_translations(kind)?;
+
+    Ok(translations
+        .into_iter()
+        .filter_map(|translation| {
+            let current = translate(&translation.raw).filter(|t| *t != translation.translated)?;
+            let display_name =
+                display_mode.render(kind, &translation.raw, &translation.translated, mapping);
+            Some((display_name, (translation.raw, current)))
+        })
+        .collect())
+}
+
+/// Works out the new calibre name for `name`. A raw name gets its translation
+/// recorded and shown in the display mode, a translated name only changes if
+/// its recorded translation has changed since.
+async fn retranslate(
+    archive_db: &Mutex<ArchiveDb>,
+    display_mode: DisplayMode,
+    mapping: &MappingProfile,
+    kind: TranslationKind,
+    name: &str,
+    translation: Option<String>,
+    change: Option<&(String, String)>,
+) -> Result<Option<String>> {
+    let (raw, translated) = match (translation, change) {
+        (Some(translation), _) => (name, translation),
+        (None, Some((raw, translated))) => (raw.as_str(), translated.clone()),
+        (None, None) => return Ok(None),
+    };
+    archive_db
+        .lock()
+        .await
+        .save_translation(kind, raw, &translated)?;
+    let display_name = display_mode.render(kind, raw, &translated, mapping);
+    Ok((display_name != name).then_some(display_name))
+}
 
-pub async fn replace_book_metadata(
-    calibre_client: Arc<Mutex<CalibreClient>>,
+/// Renames every author, publisher and tag EhArchive has translated to the
+/// given display mode. A name several raw names translate to is split by the
+/// books recorded for each, and a name without a recorded translation is
+/// looked up in the tag database.
+pub async fn revert_metadata(
+    calibre: Arc<Mutex<Calibre>>,
+    archive_db: Arc<Mutex<ArchiveDb>>,
     tag_db: Arc<Mutex<EhTagDb>>,
-    client: EhClient,
-    is_exhentai: bool,
-    url: String,
+    display_mode: DisplayMode,
+    mapping: &MappingProfile,
 ) -> Result<()> {
-    let captures = URL_REGEX
-        .captures(&url)
-        .ok_or_else(|| anyhow!("Invalid URL format"))?;
-    let gid = captures
-        .get(1)
-        .ok_or_else(|| anyhow!("Cannot extract gid"))?
-        .as_str();
-    let token = captures
-        .get(2)
-        .ok_or_else(|| anyhow!("Cannot extract token"))?
-        .as_str();
-    let identifier = format!("{}_{}_{}", gid, token, if is_exhentai { 1 } else { 0 });
-    let book_id = {
-        let mut client = calibre_client.lock().await;
-        match client.find_book_id_by_identifier("ehentai", &identifier) {
-            Ok(Some(id)) => id,
-            Ok(None) => {
-                return Err(anyhow!("No book found with identifier: {}", identifier));
+    for kind in [
+        TranslationKind::Author,
+        TranslationKind::Publisher,
+        TranslationKind::Tag,
+    ] {
+        let translations = archive_db.lock().await.get_translations(kind)?;
+        // Calibre may show any of the modes, so all of them map back
+        let mut candidates: HashMap<String, Vec<(String, String)>> = HashMap::new();
+        for translation in translations {
+            for mode in [
+                DisplayMode::Raw,
+                DisplayMode::Translated,
+                DisplayMode::TranslatedRaw,
+            ] {
+                let name = mode.render(kind, &translation.raw, &translation.translated, mapping);
+                let entry = candidates.entry(name).or_default();
+                if !entry.iter().any(|(raw, _)| *raw == translation.raw) {
+                    entry.push((translation.raw.clone(), translation.translated.clone()));
+                }
             }
-            Err(e) => return Err(anyhow!(e)),
         }
-    };
+        let translation_books = archive_db.lock().await.get_translation_books(kind)?;
 
-    let body = GalleryMetadataRequest::new(vec![GIDListItem::from(url)]);
-    let body = serde_json::to_string(&body).unwrap();
-    let api_url = Url::parse(EH_API_URL).unwrap();
-    let response: GalleryMetadataResponse = client
-        .post_json(api_url, body)
-        .await
-        .map_err(|e| anyhow!(e))?;
-    let metadata = response
-        .gmetadata
-        .into_iter()
-        .next()
-        .ok_or_else(|| anyhow!("No metadata found"))?;
-    let gid_token = format!("{}_{}", metadata.gid, metadata.token);
+        let mut calibre = calibre.lock().await;
+        let Calibre { client, db } = &mut *calibre;
+        let names_in_calibre: Vec<(i32, String)> = match kind {
+            TranslationKind::Author => client
+                .get_all_authors()
+                .map(|authors| authors.into_iter().map(|a| (a.id, a.name)).collect()),
+            TranslationKind::Publisher => client
+                .get_all_publishers()
+                .map(|publishers| publishers.into_iter().map(|p| (p.id, p.name)).collect()),
+            TranslationKind::Tag => client
+                .get_all_tags()
+                .map(|tags| tags.into_iter().map(|t| (t.id, t.name)).collect()),
+        }
+        .map_err(|e| anyhow!("{}", e))?;
+
+        let mut updated_count = 0;
+        for (id, name) in names_in_calibre {
+            let display_name = match candidates.get(&name).map(Vec::as_slice) {
+                Some([(raw, translated)]) => display_mode.render(kind, raw, translated, mapping),
+                Some(translations) => {
+                    // Only books recorded for one of the raw names can move
+                    let mut targets: HashMap<String, Vec<i32>> = HashMap::new();
+                    for book_id in db.get_linked_books(kind, id)? {
+                        let translation = translations.iter().find(|(raw, _)| {
+                            translation_books
+                                .get(raw)
+                                .is_some_and(|books| books.contains(&book_id))
+                        });
+                        if let Some((raw, translated)) = translation {
+                            targets
+                                .entry(display_mode.render(kind, raw, translated, mapping))
+                                .or_default()
+                                .push(book_id);
+                        }
+                    }
+                    for (display_name, book_ids) in targets {
+                        if display_name == name {
+                            continue;
+                        }
+                        db.relink_books(kind, id, &book_ids, &display_name)?;
+                        log::info!(
+                            "Moved {} books from {} {name} to {display_name}",
+                            book_ids.len(),
+                            kind.as_str()
+                        );
+                        updated_count += 1;
+                    }
+                    continue;
+                }
+                None => {
+                    let Some(raw) = find_raw_name(&tag_db, mapping, kind, &name).await? else {
+                        continue;
+                    };
+                    archive_db
+                        .lock()
+                        .await
+                        .save_translation(kind, &raw, &name)?;
+                    display_mode.render(kind, &raw, &name, mapping)
+                }
+            };
+            if display_name == name {
+                continue;
+            }
+            match kind {
+                TranslationKind::Author => {
+                    client.replace_author_with_translation(id, &display_name)
+                }
+                TranslationKind::Publisher => {
+                    client.replace_publisher_with_translation(id, &display_name)
+                }
+                TranslationKind::Tag => client.replace_tag_with_translation(id, &display_name),
+            }
+            .map_err(|e| anyhow!("{}", e))?;
+            log::info!("Renamed {} {name} to {display_name}", kind.as_str());
+            updated_count += 1;
+        }
+
+        log::info!(
+            "Switched {updated_count} {}s in calibre to {} names",
+            kind.as_str(),
+            display_mode.as_str()
+        );
+    }
+
+    Ok(())
+}
+
+/// Looks up the raw name a calibre name without a recorded translation was
+/// translated from. Ambiguous names are left alone.
+async fn find_raw_name(
+    tag_db: &Mutex<EhTagDb>,
+    mapping: &MappingProfile,
+    kind: TranslationKind,
+    name: &str,
+) -> Result<Option<String>> {
+    let mut tag_db = tag_db.lock().await;
+    let mut raw_names = HashSet::new();
+    match kind {
+        TranslationKind::Tag if mapping.prefix_namespaces => {
+            let Some((tag_namespace, tag_name)) = mapping.parse_tag(name) else {
+                return Ok(None);
+            };
+            let mut namespaces = tag_db.find_raw_tags("rows", tag_namespace)?;
+            if namespaces.is_empty() {
+                namespaces.push(tag_namespace.to_string());
+            }
+            for namespace in namespaces {
+                for raw_tag in tag_db.find_raw_tags(&namespace, tag_name)? {
+                    raw_names.insert(mapping.format_tag(&namespace, &raw_tag));
+                }
+            }
+        }
+        // Tags without the prefix are looked up by name, like authors
+        _ => {
+            let field = match kind {
+                TranslationKind::Author => CalibreField::Authors,
+                TranslationKind::Publisher => CalibreField::Publishers,
+                TranslationKind::Tag => CalibreField::Tags,
+            };
+            let tag_namespaces = tag_db.tag_namespaces();
+            for namespace in mapping.namespaces(field, tag_namespaces.iter().map(String::as_str)) {
+                raw_names.extend(tag_db.find_raw_tags(namespace, name)?);
+            }
+        }
+    }
+    // The name may just as well be untranslated
+    if raw_names.contains(name) {
+        return Ok(None);
+    }
+
+    if raw_names.len() > 1 {
+        log::warn!(
+            "{} {name} translates several raw names, left unchanged",
+            kind.as_str()
+        );
+    }
+
+    let mut raw_names = raw_names.into_iter();
+    Ok(match (raw_names.next(), raw_names.next()) {
+        (Some(raw), None) => Some(raw),
+        _ => None,
+    })
+}
+
+/// Finds the book of a gallery URL and fetches its current metadata, apply it
+/// with `apply_book_metadata`.
+pub async fn fetch_book_metadata(
+    calibre: &Arc<Mutex<Calibre>>,
+    client: &EhClient,
+    is_exhentai: bool,
+    url: &str,
+) -> Result<(i32, GalleryMetadata)> {
+    let (gid, token) = parse_gallery_url(url).ok_or_else(|| anyhow!("Invalid URL format"))?;
+    let identifier = gallery_identifier(gid, &token, is_exhentai);
+    let book_id = find_book_id(calibre, &identifier)
+        .await?
+        .ok_or_else(|| anyhow!("No book found with identifier: {}", identifier))?;
+
+    let metadata = fetch_gallery_metadata(client, url).await?;
     g_info!(
-        gid_token,
+        format!("{}_{}", metadata.gid, metadata.token),
         "Gallery metadata parsed successfully. Title: {}",
         metadata.title
     );
 
+    Ok((book_id, metadata))
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn apply_book_metadata(
+    calibre: Arc<Mutex<Calibre>>,
+    tag_db: Arc<Mutex<EhTagDb>>,
+    archive_db: Arc<Mutex<ArchiveDb>>,
+    is_exhentai: bool,
+    mapping: &MappingProfile,
+    book_id: i32,
+    metadata: GalleryMetadata,
+) -> Result<()> {
+    let gid_token = format!("{}_{}", metadata.gid, metadata.token);
+    let custom_columns = mapping.custom_column_values(&metadata);
+    let comments = if mapping.title_comments {
+        title_comments(&metadata)
+    } else {
+        None
+    };
     let (
         book_dto,
         authors_dto,
@@ -426,7 +1001,17 @@ pub async fn replace_book_metadata(
         identifiers_dto,
         rating_dto,
         _,
-    ) = gallery_to_dto(tag_db, is_exhentai, None, metadata).await?;
+        series,
+        links,
+    ) = gallery_to_dto(
+        tag_db,
+        archive_db.clone(),
+        is_exhentai,
+        mapping,
+        None,
+        metadata,
+    )
+    .await?;
 
     let dto = ReplaceLibraryEntryDto {
         book: book_dto,
@@ -439,12 +1024,65 @@ pub async fn replace_book_metadata(
     };
 
     g_info!(gid_token, "Replacing book metadata for book_id: {book_id}");
-    calibre_client
-        .lock()
-        .await
+    let mut calibre = calibre.lock().await;
+    calibre
+        .client
         .replace_book_metadata(book_id, dto)
         .map_err(|e| anyhow!("{}", e))?;
+    write_series(&mut calibre.db, book_id, series, &gid_token)?;
+    write_comments(&mut calibre.db, book_id, comments)?;
+    write_custom_columns(&mut calibre.db, book_id, custom_columns, &gid_token)?;
+    archive_db
+        .lock()
+        .await
+        .set_book_translations(book_id, &links)?;
     g_info!(gid_token, "Book {book_id} metadata replaced successfully");
 
     Ok(())
 }
+
+pub async fn replace_book_file(
+    calibre: Arc<Mutex<Calibre>>,
+    book_id: i32,
+    cbz_path: &str,
+    cover_path: Option<&str>,
+    gid_token: &str,
+) -> Result<()> {
+    let (book_dir, file_path) = calibre
+        .lock()
+        .await
+        .db
+        .get_book_file(book_id, "CBZ")?
+        .ok_or_else(|| anyhow!("Book {} has no CBZ file", book_id))?;
+
+    g_info!(
+        gid_token,
+        "Replacing file of book {book_id}: {}",
+        file_path.display()
+    );
+    // Copy next to the target first so the library never holds a partial file
+    let part_path = file_path.with_extension("cbz.part");
+    let size = tokio::fs::copy(cbz_path, &part_path).await?;
+    tokio::fs::rename(&part_path, &file_path).await?;
+
+    // calibre always keeps the cover as cover.jpg
+    let has_cover = match cover_path {
+        Some(cover_path) => {
+            let cover_path = cover_path.to_string();
+            let cover_part_path = book_dir.join("cover.jpg.part");
+            let part = cover_part_path.clone();
+            tokio::task::spawn_blocking(move || save_jpeg_cover(&cover_path, &part)).await??;
+            tokio::fs::rename(&cover_part_path, book_dir.join("cover.jpg")).await?;
+            true
+        }
+        None => false,
+    };
+    calibre
+        .lock()
+        .await
+        .db
+        .set_book_file_replaced(book_id, "CBZ", size as i64, has_cover)?;
+    g_info!(gid_token, "Book {book_id} file replaced successfully");
+
+    Ok(())
+}
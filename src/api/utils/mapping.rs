@@ -0,0 +1,317 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{Result, anyhow};
+use libeh::dto::api::GalleryMetadata;
+use serde::Deserialize;
+
+use super::{TagPrefixes, posted_time, title::parse_title};
+use crate::calibre_db::{ColumnType, ColumnValue};
+
+const NAMESPACE_PLACEHOLDER: &str = "{namespace}";
+const NAME_PLACEHOLDER: &str = "{name}";
+
+/// The calibre fields a tag namespace can be written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CalibreField {
+    Authors,
+    Publishers,
+    Language,
+    Tags,
+}
+
+/// Gallery fields that can be written to calibre custom columns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GalleryField {
+    Filecount,
+    Filesize,
+    Posted,
+    Uploader,
+    Category,
+    Torrentcount,
+    Expunged,
+    TitleJpn,
+    /// The full English title.
+    Title,
+    /// Parsed from the title, the Japanese one if there is one.
+    Event,
+    Circle,
+    Artist,
+    Parody,
+    Language,
+    Translator,
+    Dl,
+}
+
+impl GalleryField {
+    pub const fn column_type(&self) -> ColumnType {
+        match self {
+            Self::Filecount | Self::Filesize | Self::Torrentcount => ColumnType::Int,
+            Self::Posted => ColumnType::Datetime,
+            Self::Uploader
+            | Self::Category
+            | Self::Event
+            | Self::Circle
+            | Self::Artist
+            | Self::Parody
+            | Self::Language
+            | Self::Translator => ColumnType::Text,
+            Self::Expunged | Self::Dl => ColumnType::Bool,
+            Self::TitleJpn | Self::Title => ColumnType::Comments,
+        }
+    }
+
+    /// Column name shown in calibre when the column is created.
+    pub const fn display_name(&self) -> &'static str {
+        match self {
+            Self::Filecount => "页数",
+            Self::Filesize => "文件大小",
+            Self::Posted => "上传时间",
+            Self::Uploader => "上传者",
+            Self::Category => "分类",
+            Self::Torrentcount => "种子数",
+            Self::Expunged => "已删除",
+            Self::TitleJpn => "日文标题",
+            Self::Title => "英文标题",
+            Self::Event => "展会",
+            Self::Circle => "社团",
+            Self::Artist => "作者",
+            Self::Parody => "原作",
+            Self::Language => "语言",
+            Self::Translator => "翻译",
+            Self::Dl => "DL版",
+        }
+    }
+
+    pub fn value(&self, metadata: &GalleryMetadata) -> Option<ColumnValue> {
+        let text = |s: &str| (!s.is_empty()).then(|| ColumnValue::Text(s.to_string()));
+        let parsed = || {
+            parse_title(if metadata.title_jpn.is_empty() {
+                &metadata.title
+            } else {
+                &metadata.title_jpn
+            })
+        };
+        match self {
+            Self::Filecount => metadata.filecount.parse().ok().map(ColumnValue::Int),
+            Self::Filesize => Some(ColumnValue::Int(metadata.filesize)),
+            Self::Posted => posted_time(metadata).map(ColumnValue::Datetime),
+            Self::Uploader => text(&metadata.uploader),
+            Self::Category => text(&metadata.category),
+            Self::Torrentcount => metadata.torrentcount.parse().ok().map(ColumnValue::Int),
+            Self::Expunged => Some(ColumnValue::Bool(metadata.expunged)),
+            Self::TitleJpn => text(&metadata.title_jpn),
+            Self::Title => text(&metadata.title),
+            Self::Event => parsed().event.map(ColumnValue::Text),
+            Self::Circle => parsed().circle.map(ColumnValue::Text),
+            Self::Artist => parsed().artist.map(ColumnValue::Text),
+            Self::Parody => parsed().parody.map(ColumnValue::Text),
+            Self::Language => parsed().language.map(ColumnValue::Text),
+            Self::Translator => parsed().translator.map(ColumnValue::Text),
+            Self::Dl => Some(ColumnValue::Bool(parsed().dl)),
+        }
+    }
+}
+
+/// Which title of the gallery becomes the calibre title, the short forms
+/// leave out the bracketed parts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TitleForm {
+    #[default]
+    Japanese,
+    JapaneseFull,
+    English,
+    EnglishFull,
+}
+
+impl TitleForm {
+    /// Picks the title, falling back to the other language if it is missing.
+    pub fn select(&self, title: &str, title_jpn: &str) -> String {
+        let (preferred, fallback) = match self {
+            Self::Japanese | Self::JapaneseFull => (title_jpn, title),
+            Self::English | Self::EnglishFull => (title, title_jpn),
+        };
+        let title = if preferred.is_empty() {
+            fallback
+        } else {
+            preferred
+        };
+        match self {
+            Self::JapaneseFull | Self::EnglishFull => title.to_string(),
+            Self::Japanese | Self::English => parse_title(title).title,
+        }
+    }
+}
+
+/// A calibre custom column a gallery field is written to.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CustomColumnMapping {
+    /// Lookup name without the leading `#`.
+    pub label: String,
+    /// Only used when the column is created.
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub datatype: Option<ColumnType>,
+}
+
+/// How gallery metadata is laid out in calibre, loaded from a TOML or JSON
+/// file. Missing keys keep the default layout.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MappingProfile {
+    /// Fields for each namespace, an empty list drops the namespace.
+    pub fields: HashMap<String, Vec<CalibreField>>,
+    /// Fields for namespaces not listed in `fields`.
+    pub default_fields: Vec<CalibreField>,
+    /// Without the prefix tags are only their name.
+    pub prefix_namespaces: bool,
+    pub tag_template: String,
+    /// Empty to leave the category out.
+    pub category_template: String,
+    /// Placeholders for galleries without one, empty for none.
+    pub unknown_author: String,
+    pub unknown_publisher: String,
+    pub default_language: String,
+    pub title_form: TitleForm,
+    /// Keep the full titles and their parsed parts in the book comments,
+    /// next to whatever else the comments hold.
+    pub title_comments: bool,
+    /// Put multi-part galleries into a calibre series.
+    pub detect_series: bool,
+    /// Gallery fields written to custom columns, none by default.
+    pub custom_columns: HashMap<GalleryField, CustomColumnMapping>,
+    /// Set from the command line.
+    #[serde(skip)]
+    pub tag_prefixes: TagPrefixes,
+}
+
+impl Default for MappingProfile {
+    fn default() -> Self {
+        Self {
+            fields: HashMap::from([
+                ("artist".to_string(), vec![CalibreField::Authors]),
+                ("group".to_string(), vec![CalibreField::Publishers]),
+                (
+                    "language".to_string(),
+                    vec![CalibreField::Language, CalibreField::Tags],
+                ),
+            ]),
+            default_fields: vec![CalibreField::Tags],
+            prefix_namespaces: true,
+            tag_template: "{namespace}:{name}".to_string(),
+            category_template: "分类:{name}".to_string(),
+            unknown_author: "Unknown".to_string(),
+            unknown_publisher: "Unknown".to_string(),
+            default_language: "jpn".to_string(),
+            title_form: TitleForm::default(),
+            title_comments: true,
+            detect_series: true,
+            custom_columns: HashMap::new(),
+            tag_prefixes: TagPrefixes::default(),
+        }
+    }
+}
+
+impl MappingProfile {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let profile = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => toml::from_str(&content)?,
+            Some("json") => serde_json::from_str(&content)?,
+            _ => {
+                return Err(anyhow!(
+                    "Unsupported mapping profile format: {}",
+                    path.display()
+                ));
+            }
+        };
+        Ok(profile)
+    }
+
+    /// The values of the configured custom columns, by label.
+    pub fn custom_column_values(&self, metadata: &GalleryMetadata) -> Vec<(String, ColumnValue)> {
+        self.custom_columns
+            .iter()
+            .filter_map(|(field, column)| {
+                field
+                    .value(metadata)
+                    .map(|value| (column.label.clone(), value))
+            })
+            .collect()
+    }
+
+    pub fn fields(&self, namespace: &str) -> &[CalibreField] {
+        self.fields.get(namespace).unwrap_or(&self.default_fields)
+    }
+
+    /// Namespaces out of the given ones written to the field, the ones
+    /// listed in `fields` first and then those `default_fields` covers.
+    pub fn namespaces<'a>(
+        &self,
+        field: CalibreField,
+        namespaces: impl IntoIterator<Item = &'a str>,
+    ) -> Vec<&'a str> {
+        let mut namespaces: Vec<_> = namespaces
+            .into_iter()
+            .filter(|namespace| self.fields(namespace).contains(&field))
+            .collect();
+        namespaces
+            .sort_unstable_by_key(|namespace| (!self.fields.contains_key(*namespace), *namespace));
+        namespaces.dedup();
+        namespaces
+    }
+
+    pub fn format_tag(&self, namespace: &str, name: &str) -> String {
+        if !self.prefix_namespaces {
+            return name.to_string();
+        }
+        self.tag_template
+            .replace(NAMESPACE_PLACEHOLDER, namespace)
+            .replace(NAME_PLACEHOLDER, name)
+    }
+
+    /// Splits a tag formatted by `format_tag` back into namespace and name.
+    pub fn parse_tag<'a>(&self, tag: &'a str) -> Option<(&'a str, &'a str)> {
+        if !self.prefix_namespaces {
+            return None;
+        }
+        let namespace_pos = self.tag_template.find(NAMESPACE_PLACEHOLDER)?;
+        let name_pos = self.tag_template.find(NAME_PLACEHOLDER)?;
+
+        let (first, second) = if namespace_pos < name_pos {
+            (
+                (namespace_pos, NAMESPACE_PLACEHOLDER),
+                (name_pos, NAME_PLACEHOLDER),
+            )
+        } else {
+            (
+                (name_pos, NAME_PLACEHOLDER),
+                (namespace_pos, NAMESPACE_PLACEHOLDER),
+            )
+        };
+        let prefix = &self.tag_template[..first.0];
+        let separator = &self.tag_template[first.0 + first.1.len()..second.0];
+        let suffix = &self.tag_template[second.0 + second.1.len()..];
+        if separator.is_empty() {
+            return None;
+        }
+
+        let (a, b) = tag
+            .strip_prefix(prefix)?
+            .strip_suffix(suffix)?
+            .split_once(separator)?;
+        if namespace_pos < name_pos {
+            Some((a, b))
+        } else {
+            Some((b, a))
+        }
+    }
+
+    pub fn format_category(&self, name: &str) -> Option<String> {
+        (!self.category_template.is_empty())
+            .then(|| self.category_template.replace(NAME_PLACEHOLDER, name))
+    }
+}
@@ -1,11 +1,191 @@
+pub mod archive;
 pub mod calibre;
+pub mod mapping;
+pub mod retry;
+pub mod title;
 
-use std::{fs::File, io};
+use std::{fs::File, io, path::Path, time::Duration};
 
-use anyhow::Result;
-use libeh::dto::keyword::Keyword;
+use anyhow::{Result, anyhow};
+use chrono::{DateTime, Utc};
+use image::ImageFormat;
+use libeh::{
+    client::client::EhClient,
+    dto::{
+        api::{GIDListItem, GalleryMetadata, GalleryMetadataRequest},
+        keyword::Keyword,
+    },
+};
+use log::warn;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::Url;
+use serde::{Deserialize, Deserializer, de::DeserializeOwned};
 use zip::ZipArchive;
 
+use super::EH_API_URL;
+use retry::{PermanentError, RetryPolicy, eh_error, is_rate_limited};
+
+/// The gallery metadata API accepts at most 25 galleries per request.
+const METADATA_BATCH_SIZE: usize = 25;
+
+/// Pause between batches, the API rate limits bursts of requests.
+const METADATA_BATCH_DELAY: Duration = Duration::from_secs(1);
+
+const RATE_LIMIT_RETRY: RetryPolicy = RetryPolicy {
+    max_attempts: 5,
+    base_delay: Duration::from_secs(5),
+    jitter: Duration::from_secs(1),
+};
+
+static URL_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"/g/(\d+)/([a-f0-9]+)/?").unwrap());
+
+pub fn parse_gallery_url(url: &str) -> Option<(i64, String)> {
+    let captures = URL_REGEX.captures(url)?;
+    let gid = captures.get(1)?.as_str().parse().ok()?;
+    let token = captures.get(2)?.as_str().to_string();
+    Some((gid, token))
+}
+
+pub async fn fetch_gallery_metadata(client: &EhClient, url: &str) -> Result<GalleryMetadata> {
+    let mut metadata = post_gallery_metadata_batch(client, &[url.to_string()]).await?;
+    match metadata.pop() {
+        Some(Ok(metadata)) => Ok(metadata),
+        Some(Err(e)) => Err(PermanentError(format!("Gallery metadata unavailable: {e}")).into()),
+        None => Err(PermanentError("No metadata found".to_string()).into()),
+    }
+}
+
+/// Galleries the API reports an error for, e.g. removed ones, are left out.
+pub async fn fetch_gallery_metadata_batch(
+    client: &EhClient,
+    urls: &[String],
+) -> Result<Vec<GalleryMetadata>> {
+    let metadata = post_gallery_metadata_batch(client, urls).await?;
+    Ok(metadata.into_iter().filter_map(Result::ok).collect())
+}
+
+/// The version fields of a gallery's metadata, `current_gid` points at the
+/// newest version of the gallery.
+#[derive(Debug, Deserialize)]
+pub struct GalleryVersion {
+    pub gid: i64,
+    #[serde(default, deserialize_with = "deserialize_gid")]
+    pub current_gid: Option<i64>,
+    pub current_key: Option<String>,
+}
+
+impl GalleryVersion {
+    /// Returns the gid and token of the newest version if it is not this one.
+    pub fn newer_version(&self) -> Option<(i64, &str)> {
+        match (self.current_gid, self.current_key.as_deref()) {
+            (Some(gid), Some(key)) if gid > self.gid => Some((gid, key)),
+            _ => None,
+        }
+    }
+}
+
+// The API returns these gids as strings
+fn deserialize_gid<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i64>, D::Error> {
+    let value = Option::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(match value {
+        Some(serde_json::Value::Number(n)) => n.as_i64(),
+        Some(serde_json::Value::String(s)) => s.parse().ok(),
+        _ => None,
+    })
+}
+
+pub async fn fetch_gallery_versions(
+    client: &EhClient,
+    urls: &[String],
+) -> Result<Vec<GalleryVersion>> {
+    let versions = post_gallery_metadata_batch(client, urls).await?;
+    Ok(versions.into_iter().filter_map(Result::ok).collect())
+}
+
+#[derive(Deserialize)]
+struct GalleryMetadataBatch {
+    #[serde(default)]
+    gmetadata: Vec<serde_json::Value>,
+    error: Option<String>,
+}
+
+/// Returns the metadata of each gallery, or the error the API gave for it.
+async fn post_gallery_metadata_batch<T: DeserializeOwned>(
+    client: &EhClient,
+    urls: &[String],
+) -> Result<Vec<Result<T, String>>> {
+    let api_url = Url::parse(EH_API_URL).unwrap();
+    let mut result = Vec::with_capacity(urls.len());
+
+    for (i, chunk) in urls.chunks(METADATA_BATCH_SIZE).enumerate() {
+        if i > 0 {
+            tokio::time::sleep(METADATA_BATCH_DELAY).await;
+        }
+        let gid_list = chunk.iter().cloned().map(GIDListItem::from).collect();
+        let body = GalleryMetadataRequest::new(gid_list);
+        let body = serde_json::to_string(&body).unwrap();
+        let mut attempt = 1;
+        let response: GalleryMetadataBatch = loop {
+            match client
+                .post_json(api_url.clone(), body.clone())
+                .await
+                .map_err(|e| eh_error(anyhow!(e)))
+            {
+                Err(e) if attempt < RATE_LIMIT_RETRY.max_attempts && is_rate_limited(&e) => {
+                    let delay = RATE_LIMIT_RETRY.delay(attempt);
+                    warn!("Gallery API rate limited, retrying in {delay:?}");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                result => break result?,
+            }
+        };
+        if let Some(error) = response.error {
+            return Err(PermanentError(format!("Gallery API error: {error}")).into());
+        }
+        result.extend(response.gmetadata.into_iter().map(|item| {
+            match item.get("error").and_then(|e| e.as_str()) {
+                Some(error) => Err(error.to_string()),
+                None => serde_json::from_value(item).map_err(|e| e.to_string()),
+            }
+        }));
+    }
+
+    Ok(result)
+}
+
+pub fn gallery_url(gid: i64, token: &str, is_exhentai: bool) -> String {
+    let host = if is_exhentai {
+        "exhentai.org"
+    } else {
+        "e-hentai.org"
+    };
+    format!("https://{host}/g/{gid}/{token}/")
+}
+
+/// The upload time of the gallery, `posted` is a unix timestamp.
+pub fn posted_time(metadata: &GalleryMetadata) -> Option<DateTime<Utc>> {
+    metadata
+        .posted
+        .parse()
+        .ok()
+        .and_then(|t| DateTime::from_timestamp(t, 0))
+}
+
+pub fn parse_size(size: &str) -> Option<u64> {
+    let (value, unit) = size.trim().split_once(' ')?;
+    let value: f64 = value.parse().ok()?;
+    let multiplier = match unit.trim() {
+        "B" => 1u64,
+        "KiB" | "KB" => 1 << 10,
+        "MiB" | "MB" => 1 << 20,
+        "GiB" | "GB" => 1 << 30,
+        _ => return None,
+    };
+    Some((value * multiplier as f64) as u64)
+}
+
 fn parse_category(category: String) -> Option<String> {
     match category.as_str() {
         "Misc" => Some("misc".to_string()),
@@ -22,9 +202,20 @@ fn parse_category(category: String) -> Option<String> {
     }
 }
 
-fn parse_tag(tag: &Keyword) -> Option<(&str, &str)> {
+/// Namespaces for tags without one of their own, an empty prefix drops them.
+#[derive(Debug, Clone, Default)]
+pub struct TagPrefixes {
+    pub untagged: String,
+    pub temp: String,
+}
+
+fn parse_tag<'a>(tag: &'a Keyword, prefixes: &'a TagPrefixes) -> Option<(&'a str, &'a str)> {
+    let prefixed = |prefix: &'a str, k: &'a str| (!prefix.is_empty()).then_some((prefix, k));
     match tag {
-        Keyword::Normal(_) => None,
+        // Namespaces libeh doesn't know, such as location, end up here too
+        Keyword::Normal(k) => k
+            .split_once(':')
+            .or_else(|| prefixed(&prefixes.untagged, k)),
         Keyword::Language(k) => Some(("language", k)),
         Keyword::Parody(k) => Some(("parody", k)),
         Keyword::Character(k) => Some(("character", k)),
@@ -36,11 +227,20 @@ fn parse_tag(tag: &Keyword) -> Option<(&str, &str)> {
         Keyword::Mixed(k) => Some(("mixed", k)),
         Keyword::Other(k) => Some(("other", k)),
         Keyword::Reclass(k) => Some(("reclass", k)),
-        Keyword::Temp(_) => None,
+        Keyword::Temp(k) => prefixed(&prefixes.temp, k),
         Keyword::Uploader(k) => Some(("uploader", k)),
     }
 }
 
+pub fn validate_archive(path: &str) -> Result<()> {
+    let file = File::open(path)?;
+    let archive = ZipArchive::new(file)?;
+    if archive.is_empty() {
+        return Err(anyhow!("Archive is empty: {}", path));
+    }
+    Ok(())
+}
+
 pub fn extract_cover(cbz_path: &str, output_dir: &str) -> Result<Option<(String, String)>> {
     let file = File::open(cbz_path)?;
     let mut archive = ZipArchive::new(file)?;
@@ -52,7 +252,7 @@ pub fn extract_cover(cbz_path: &str, output_dir: &str) -> Result<Option<(String,
         if let Some(ext) = path.extension() {
             if let Some(ext) = ext.to_str() {
                 let ext = ext.to_lowercase();
-                if ext == "jpg" || ext == "jpeg" || ext == "png" {
+                if ext == "jpg" || ext == "jpeg" || ext == "png" || ext == "webp" {
                     let output_path = format!("{output_dir}/cover.{ext}");
                     let mut output_file = File::create(&output_path)?;
                     io::copy(&mut file, &mut output_file)?;
@@ -63,3 +263,16 @@ pub fn extract_cover(cbz_path: &str, output_dir: &str) -> Result<Option<(String,
     }
     Ok(None)
 }
+
+/// Writes the cover as a JPEG, re-encoding PNG and WebP covers.
+pub fn save_jpeg_cover(cover_path: &str, output_path: &Path) -> Result<()> {
+    let lower = cover_path.to_lowercase();
+    if lower.ends_with(".jpg") || lower.ends_with(".jpeg") {
+        std::fs::copy(cover_path, output_path)?;
+        return Ok(());
+    }
+    image::open(cover_path)?
+        .to_rgb8()
+        .save_with_format(output_path, ImageFormat::Jpeg)?;
+    Ok(())
+}
@@ -0,0 +1,247 @@
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Error;
+use reqwest::StatusCode;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub jitter: Duration,
+}
+
+impl RetryPolicy {
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.base_delay * 2u32.saturating_pow(attempt.saturating_sub(1).min(10));
+        let jitter_ms = self.jitter.as_millis() as u64;
+        if jitter_ms == 0 {
+            return backoff;
+        }
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos() as u64)
+            .unwrap_or_default();
+        backoff + Duration::from_millis(nanos % (jitter_ms + 1))
+    }
+}
+
+impl FromStr for RetryPolicy {
+    type Err = String;
+
+    /// Parses `max_attempts,base_delay_ms,jitter_ms`, e.g. `3,1000,500`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.split(',').map(str::trim).collect();
+        if parts.len() != 3 {
+            return Err(format!(
+                "Expected max_attempts,base_delay_ms,jitter_ms, got: {s}"
+            ));
+        }
+        let parse = |v: &str| v.parse::<u64>().map_err(|e| format!("{v}: {e}"));
+
+        Ok(Self {
+            max_attempts: parse(parts[0])?.max(1) as u32,
+            base_delay: Duration::from_millis(parse(parts[1])?),
+            jitter: Duration::from_millis(parse(parts[2])?),
+        })
+    }
+}
+
+/// An error that will not go away by retrying, e.g. a removed gallery or
+/// insufficient GP.
+#[derive(Debug)]
+pub struct PermanentError(pub String);
+
+impl Display for PermanentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for PermanentError {}
+
+/// An error that is likely to go away by retrying, e.g. a truncated download.
+#[derive(Debug)]
+pub struct TransientError(pub String);
+
+impl Display for TransientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for TransientError {}
+
+/// Whether the request was rejected for sending too many requests.
+pub fn is_rate_limited(e: &Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<reqwest::Error>()
+            .and_then(reqwest::Error::status)
+            == Some(StatusCode::TOO_MANY_REQUESTS)
+    })
+}
+
+/// Only known transient errors are retried, anything else fails right away.
+/// Both can also be attached as context.
+pub fn is_retryable(e: &Error) -> bool {
+    if e.downcast_ref::<PermanentError>().is_some()
+        || e.chain().any(|cause| cause.is::<PermanentError>())
+    {
+        return false;
+    }
+    if e.downcast_ref::<TransientError>().is_some() {
+        return true;
+    }
+    e.chain().any(|cause| {
+        if cause.is::<TransientError>() {
+            return true;
+        }
+        match cause.downcast_ref::<reqwest::Error>() {
+            Some(e) => match e.status() {
+                Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+                None => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+            },
+            None => false,
+        }
+    })
+}
+
+/// Classifies an error of the E-Hentai client. libeh does not promise to keep
+/// the reqwest error as its source, without it the request is taken as a
+/// transient network failure.
+pub fn eh_error(e: Error) -> Error {
+    if e.chain().any(|cause| cause.is::<reqwest::Error>()) {
+        e
+    } else {
+        TransientError(format!("{e:#}")).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as StdError;
+
+    use anyhow::anyhow;
+    use axum::{Router, extract::Path, http::StatusCode as AxumStatus, routing::get};
+
+    use super::*;
+
+    /// An error keeping its cause as the source, like a wrapping client error.
+    #[derive(Debug)]
+    struct Wrapped(reqwest::Error);
+
+    impl Display for Wrapped {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "request failed")
+        }
+    }
+
+    impl StdError for Wrapped {
+        fn source(&self) -> Option<&(dyn StdError + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[test]
+    fn parses_policy() {
+        let policy: RetryPolicy = "3,1000,500".parse().unwrap();
+        assert_eq!(policy.max_attempts, 3);
+        assert_eq!(policy.base_delay, Duration::from_millis(1000));
+        assert_eq!(policy.jitter, Duration::from_millis(500));
+
+        let policy: RetryPolicy = " 0 , 10 , 0 ".parse().unwrap();
+        assert_eq!(policy.max_attempts, 1);
+        assert_eq!(policy.delay(3), Duration::from_millis(40));
+
+        for s in [
+            "",
+            "3,1000",
+            "3,1000,500,1",
+            "a,1000,500",
+            "-1,1000,500",
+            "3,,500",
+        ] {
+            assert!(s.parse::<RetryPolicy>().is_err(), "{s}");
+        }
+    }
+
+    /// Fails a request with the status code of the path.
+    async fn status_error(code: u16) -> reqwest::Error {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new().route(
+            "/{code}",
+            get(|Path(code): Path<u16>| async move { AxumStatus::from_u16(code).unwrap() }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        reqwest::get(format!("{base_url}/{code}"))
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap_err()
+    }
+
+    async fn connect_error() -> reqwest::Error {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        reqwest::get(format!("http://{addr}")).await.unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn classifies_errors() {
+        let permanent = Error::new(TransientError("truncated".to_string()))
+            .context(PermanentError("removed".to_string()));
+        assert!(!is_retryable(&permanent));
+        let permanent =
+            Error::new(status_error(503).await).context(PermanentError("removed".to_string()));
+        assert!(!is_retryable(&permanent));
+
+        assert!(is_retryable(
+            &TransientError("truncated".to_string()).into()
+        ));
+        assert!(is_retryable(
+            &Error::new(TransientError("truncated".to_string())).context("downloading")
+        ));
+
+        assert!(is_retryable(&status_error(500).await.into()));
+        assert!(is_retryable(&status_error(503).await.into()));
+        let rate_limited = status_error(429).await.into();
+        assert!(is_retryable(&rate_limited));
+        assert!(is_rate_limited(&rate_limited));
+
+        assert!(!is_retryable(&status_error(404).await.into()));
+        assert!(!is_retryable(&status_error(403).await.into()));
+        assert!(!is_rate_limited(&status_error(503).await.into()));
+
+        assert!(is_retryable(&connect_error().await.into()));
+        assert!(!is_retryable(&anyhow!("invalid archive")));
+    }
+
+    #[tokio::test]
+    async fn classifies_client_errors_by_source() {
+        // A wrapped reqwest error is classified through the source chain
+        assert!(is_retryable(&eh_error(anyhow!(Wrapped(
+            status_error(502).await
+        )))));
+        assert!(!is_retryable(&eh_error(anyhow!(Wrapped(
+            status_error(404).await
+        )))));
+        assert!(is_rate_limited(&eh_error(anyhow!(Wrapped(
+            status_error(429).await
+        )))));
+        assert!(is_retryable(&eh_error(anyhow!(Wrapped(
+            connect_error().await
+        )))));
+
+        // Without one the client error is still retried
+        let opaque = eh_error(anyhow!("error sending request"));
+        assert!(is_retryable(&opaque));
+        assert_eq!(opaque.to_string(), "error sending request");
+    }
+}
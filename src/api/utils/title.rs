@@ -0,0 +1,299 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+static SERIES_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?i)第\s*([0-9０-９一二三四五六七八九十]+)\s*[話话巻卷章回部]|\bvol(?:ume)?\.?\s*([0-9０-９]+)|(?:^|\s)[#＃]\s*([0-9０-９]+)|(前|後|后)[編编]",
+    )
+    .unwrap()
+});
+
+static DL_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)^(?:DL版|digital)$").unwrap());
+
+static LANGUAGE_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"(?i)翻訳|翻译|^(?:english|chinese|korean|japanese|russian|spanish|french|german|italian|portuguese|thai|vietnamese|indonesian|polish|中国語|中文|韓国語|英語|英訳)$",
+    )
+    .unwrap()
+});
+
+static EDIT_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)^(?:無修正|decensored|uncensored|colorized|カラー化)$").unwrap());
+
+/// The parts of a gallery title following the usual
+/// `(event) [circle (artist)] title (parody) [language] [translator] [DL版]`
+/// naming, any of them may be missing.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParsedTitle {
+    pub event: Option<String>,
+    pub circle: Option<String>,
+    pub artist: Option<String>,
+    pub title: String,
+    pub parody: Option<String>,
+    pub language: Option<String>,
+    pub translator: Option<String>,
+    pub dl: bool,
+}
+
+pub fn parse_title(full_title: &str) -> ParsedTitle {
+    let mut parsed = ParsedTitle::default();
+    let mut rest = full_title.trim();
+
+    if let Some((event, after)) = split_leading(rest, '(', ')') {
+        parsed.event = non_empty(event);
+        rest = after;
+    }
+    if let Some((author, after)) = split_leading(rest, '[', ']') {
+        match split_trailing(author, '(', ')') {
+            Some((before, artist)) if !before.is_empty() => {
+                parsed.circle = non_empty(before);
+                parsed.artist = non_empty(artist);
+            }
+            _ => parsed.artist = non_empty(author),
+        }
+        rest = after;
+    }
+
+    let mut translators = Vec::new();
+    loop {
+        if let Some((before, group)) = split_trailing(rest, '(', ')') {
+            parsed.parody = non_empty(group);
+            rest = before;
+        } else if let Some((before, group)) = split_trailing(rest, '{', '}') {
+            translators.push(group);
+            rest = before;
+        } else if let Some((before, group)) = split_trailing(rest, '[', ']') {
+            if DL_REGEX.is_match(group) {
+                parsed.dl = true;
+            } else if LANGUAGE_REGEX.is_match(group) {
+                parsed.language = non_empty(group);
+            } else if !EDIT_REGEX.is_match(group) && !group.is_empty() {
+                translators.push(group);
+            }
+            rest = before;
+        } else {
+            break;
+        }
+    }
+    translators.reverse();
+    parsed.translator = non_empty(&translators.join(", "));
+
+    parsed.title = if rest.is_empty() {
+        full_title.trim().to_string()
+    } else {
+        rest.to_string()
+    };
+    parsed
+}
+
+fn non_empty(s: &str) -> Option<String> {
+    let s = s.trim();
+    (!s.is_empty()).then(|| s.to_string())
+}
+
+/// Splits `(inner) rest` into `inner` and `rest`, allowing nested brackets.
+fn split_leading(s: &str, open: char, close: char) -> Option<(&str, &str)> {
+    let inner = s.strip_prefix(open)?;
+    let mut depth = 1;
+    for (i, c) in inner.char_indices() {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            depth -= 1;
+            if depth == 0 {
+                return Some((inner[..i].trim(), inner[i + c.len_utf8()..].trim_start()));
+            }
+        }
+    }
+    None
+}
+
+/// Splits `rest (inner)` into `rest` and `inner`, allowing nested brackets.
+fn split_trailing(s: &str, open: char, close: char) -> Option<(&str, &str)> {
+    let inner = s.strip_suffix(close)?;
+    let mut depth = 1;
+    for (i, c) in inner.char_indices().rev() {
+        if c == close {
+            depth += 1;
+        } else if c == open {
+            depth -= 1;
+            if depth == 0 {
+                return Some((inner[..i].trim_end(), inner[i + c.len_utf8()..].trim()));
+            }
+        }
+    }
+    None
+}
+
+/// Detects the part of a multi-part gallery from its title, e.g. `第2話`,
+/// `Vol. 3`, `#4` or `前編`, and returns the series name and the index.
+pub fn parse_series(title: &str) -> Option<(String, f32)> {
+    let captures = SERIES_REGEX.captures(title)?;
+    let index = if let Some(part) = captures.get(4) {
+        if part.as_str() == "前" { 1.0 } else { 2.0 }
+    } else {
+        let number = captures
+            .get(1)
+            .or_else(|| captures.get(2))
+            .or_else(|| captures.get(3))?;
+        parse_number(number.as_str())?
+    };
+
+    let start = captures.get(0)?.start();
+    let name = title[..start].trim_end_matches(|c: char| {
+        c.is_whitespace() || matches!(c, '-' | '~' | ':' | '～' | '：' | '・' | '　')
+    });
+    if name.is_empty() {
+        return None;
+    }
+
+    Some((name.to_string(), index))
+}
+
+/// Parses ASCII, full-width and simple kanji numbers up to 99.
+fn parse_number(s: &str) -> Option<f32> {
+    let digits: String = s
+        .chars()
+        .map(|c| match c {
+            '０'..='９' => char::from_u32(c as u32 - '０' as u32 + '0' as u32).unwrap_or(c),
+            _ => c,
+        })
+        .collect();
+    if let Ok(n) = digits.parse::<u32>() {
+        return Some(n as f32);
+    }
+
+    let kanji = |c: char| {
+        "一二三四五六七八九"
+            .chars()
+            .position(|k| k == c)
+            .map(|i| i as u32 + 1)
+    };
+    let chars: Vec<char> = s.chars().collect();
+    let n = match chars.iter().position(|&c| c == '十') {
+        Some(pos) => {
+            let tens = match pos {
+                0 => 1,
+                1 => kanji(chars[0])?,
+                _ => return None,
+            };
+            let ones = match &chars[pos + 1..] {
+                [] => 0,
+                [c] => kanji(*c)?,
+                _ => return None,
+            };
+            tens * 10 + ones
+        }
+        None if chars.len() == 1 => kanji(chars[0])?,
+        None => return None,
+    };
+
+    Some(n as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_series() {
+        let cases = [
+            ("作品名 第2話", Some(("作品名", 2.0))),
+            ("作品名 第２话", Some(("作品名", 2.0))),
+            ("作品名 第十二話", Some(("作品名", 12.0))),
+            ("作品名 第二十三回", Some(("作品名", 23.0))),
+            ("作品名 第三巻", Some(("作品名", 3.0))),
+            ("Title Vol. 3", Some(("Title", 3.0))),
+            ("Title volume 5", Some(("Title", 5.0))),
+            ("Title #4", Some(("Title", 4.0))),
+            ("Title ＃４", Some(("Title", 4.0))),
+            ("作品名 前編", Some(("作品名", 1.0))),
+            ("作品名 後編", Some(("作品名", 2.0))),
+            ("作品名 前编", Some(("作品名", 1.0))),
+            ("作品名 后编", Some(("作品名", 2.0))),
+            ("作品名 - 第1話", Some(("作品名", 1.0))),
+            ("作品名", None),
+            ("Title#4", None),
+            ("第2話", None),
+            ("作品名 第百話", None),
+        ];
+        for (title, expected) in cases {
+            let expected = expected.map(|(name, index)| (name.to_string(), index));
+            assert_eq!(parse_series(title), expected, "{title}");
+        }
+    }
+
+    #[test]
+    fn parses_numbers() {
+        let cases = [
+            ("3", Some(3.0)),
+            ("１２", Some(12.0)),
+            ("七", Some(7.0)),
+            ("十", Some(10.0)),
+            ("十二", Some(12.0)),
+            ("二十", Some(20.0)),
+            ("九十九", Some(99.0)),
+            ("十十", None),
+            ("一二", None),
+            ("百", None),
+        ];
+        for (s, expected) in cases {
+            assert_eq!(parse_number(s), expected, "{s}");
+        }
+    }
+
+    #[test]
+    fn parses_titles() {
+        let cases = [
+            (
+                "(C99) [サークル (作者)] タイトル (原作) [中国翻訳] [某汉化组] [DL版]",
+                ParsedTitle {
+                    event: Some("C99".to_string()),
+                    circle: Some("サークル".to_string()),
+                    artist: Some("作者".to_string()),
+                    title: "タイトル".to_string(),
+                    parody: Some("原作".to_string()),
+                    language: Some("中国翻訳".to_string()),
+                    translator: Some("某汉化组".to_string()),
+                    dl: true,
+                },
+            ),
+            (
+                "[Artist] Title [English] {Group} [Decensored]",
+                ParsedTitle {
+                    artist: Some("Artist".to_string()),
+                    title: "Title".to_string(),
+                    language: Some("English".to_string()),
+                    translator: Some("Group".to_string()),
+                    ..Default::default()
+                },
+            ),
+            (
+                "Title [Team A] [Team B]",
+                ParsedTitle {
+                    title: "Title".to_string(),
+                    translator: Some("Team A, Team B".to_string()),
+                    ..Default::default()
+                },
+            ),
+            (
+                "Just a title",
+                ParsedTitle {
+                    title: "Just a title".to_string(),
+                    ..Default::default()
+                },
+            ),
+            (
+                "[Artist]",
+                ParsedTitle {
+                    artist: Some("Artist".to_string()),
+                    title: "[Artist]".to_string(),
+                    ..Default::default()
+                },
+            ),
+        ];
+        for (title, expected) in cases {
+            assert_eq!(parse_title(title), expected, "{title}");
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::Result;
+use axum::{Json, extract::State, http::StatusCode};
+use chrono::Utc;
+use log::{error, info, warn};
+use serde_json::{Value, json};
+
+use super::{
+    DownloadType, OnExisting, OutdatedBook, VersionCheckRequest, VersionCheckResponse,
+    VersionReport,
+    utils::{fetch_gallery_versions, gallery_url},
+};
+use crate::{
+    DownloadManager,
+    archive_db::{TaskKind, TaskState},
+};
+
+/// Starts a version check in the background, the result is kept for
+/// `handle_version_report`.
+pub async fn handle_version_check(
+    State(manager): State<DownloadManager>,
+    request: Option<Json<VersionCheckRequest>>,
+) -> Result<Json<VersionCheckResponse>, (StatusCode, Json<Value>)> {
+    let Json(request) = request.unwrap_or_default();
+    let task_id = manager
+        .create_task(TaskKind::VersionCheck, "", request.upgrade, None)
+        .await
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(json!({"msg": format!("启动画廊新版本检查任务失败: {}", e)})),
+            )
+        })?;
+    manager
+        .spawn_version_check(task_id.clone(), request.upgrade)
+        .await;
+
+    Ok(Json(VersionCheckResponse {
+        message: "画廊新版本检查任务已启动".to_string(),
+        task_id,
+    }))
+}
+
+pub async fn handle_version_report(
+    State(manager): State<DownloadManager>,
+) -> Result<Json<VersionReport>, (StatusCode, Json<Value>)> {
+    manager
+        .version_report
+        .lock()
+        .await
+        .clone()
+        .map(Json)
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                Json(json!({"msg": "尚未完成过画廊新版本检查"})),
+            )
+        })
+}
+
+impl DownloadManager {
+    pub fn spawn_version_check_job(&self, interval: u64, upgrade: Option<DownloadType>) {
+        if interval == 0 {
+            return;
+        }
+        let manager = self.clone();
+
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(Duration::from_secs(interval));
+            // The first tick completes immediately, don't scan right at startup
+            timer.tick().await;
+            loop {
+                timer.tick().await;
+                let running = manager
+                    .active_tasks
+                    .lock()
+                    .await
+                    .values()
+                    .any(|t| t.kind == TaskKind::VersionCheck && !t.state.is_finished());
+                if running {
+                    info!("Version check still running, skipping this one");
+                    continue;
+                }
+                match manager
+                    .create_task(TaskKind::VersionCheck, "", upgrade, None)
+                    .await
+                {
+                    Ok(task_id) => manager.spawn_version_check(task_id, upgrade).await,
+                    Err(e) => error!("Failed to start version check: {e:?}"),
+                }
+            }
+        });
+    }
+
+    pub(crate) async fn spawn_version_check(&self, task_id: String, upgrade: Option<DownloadType>) {
+        let manager = self.clone();
+        let id = task_id.clone();
+
+        let handle = tokio::spawn(async move {
+            let result = async {
+                manager
+                    .enter_stage(&task_id, TaskState::FetchingMetadata)
+                    .await?;
+                let report = manager.check_gallery_versions(&task_id, upgrade).await?;
+                info!(
+                    "Version check done: {} of {} books outdated",
+                    report.outdated.len(),
+                    report.checked
+                );
+                *manager.version_report.lock().await = Some(report);
+                Ok(())
+            }
+            .await;
+            if let Err(e) = &result {
+                error!("Version check failed: {e:?}");
+            }
+            manager.finish_task(&task_id, &result).await;
+        });
+        self.track_task_handle(&id, handle.abort_handle()).await;
+    }
+
+    async fn check_gallery_versions(
+        &self,
+        task_id: &str,
+        upgrade: Option<DownloadType>,
+    ) -> Result<VersionReport> {
+        let identifiers = self.calibre.lock().await.db.get_identifiers("ehentai")?;
+
+        // Identifiers look like `{gid}_{token}_{is_exhentai}`
+        let books: Vec<(i32, i64, String)> = identifiers
+            .into_iter()
+            .filter_map(|(book_id, value)| {
+                let mut parts = value.split('_');
+                let gid = parts.next()?.parse().ok()?;
+                let token = parts.next()?.to_string();
+                Some((book_id, gid, token))
+            })
+            .collect();
+        info!("Checking {} books for newer gallery versions", books.len());
+
+        let urls: Vec<String> = books
+            .iter()
+            .map(|(_, gid, token)| gallery_url(*gid, token, self.is_exhentai))
+            .collect();
+        let versions: HashMap<_, _> = fetch_gallery_versions(&self.client, &urls)
+            .await?
+            .into_iter()
+            .map(|version| (version.gid, version))
+            .collect();
+
+        let mut outdated = Vec::new();
+        for (book_id, gid, token) in &books {
+            let Some(version) = versions.get(gid) else {
+                continue;
+            };
+            let Some((current_gid, current_token)) = version.newer_version() else {
+                continue;
+            };
+            info!(
+                "Book {book_id} ({gid}_{token}) has a newer version: {current_gid}_{current_token}"
+            );
+
+            let task_id = match upgrade {
+                Some(download_type) => {
+                    let url = gallery_url(current_gid, current_token, self.is_exhentai);
+                    self.queue_upgrade(*book_id, url, download_type).await
+                }
+                None => None,
+            };
+            outdated.push(OutdatedBook {
+                book_id: *book_id,
+                gid: *gid,
+                token: token.clone(),
+                current_gid,
+                current_token: current_token.to_string(),
+                task_id,
+            });
+        }
+
+        Ok(VersionReport {
+            task_id: task_id.to_string(),
+            finished_at: Utc::now().timestamp(),
+            checked: books.len(),
+            outdated,
+        })
+    }
+
+    async fn queue_upgrade(
+        &self,
+        book_id: i32,
+        url: String,
+        download_type: DownloadType,
+    ) -> Option<String> {
+        let in_progress = {
+            let tasks = self.active_tasks.lock().await;
+            tasks
+                .values()
+                .find(|t| t.url == url && !t.state.is_finished())
+                .map(|t| t.id.clone())
+        };
+        if in_progress.is_some() {
+            return in_progress;
+        }
+
+        let task_id = match self
+            .create_task(TaskKind::Upgrade, &url, Some(download_type), None)
+            .await
+        {
+            Ok(task_id) => task_id,
+            Err(e) => {
+                warn!("Failed to queue upgrade of book {book_id}: {e}");
+                return None;
+            }
+        };
+        self.update_task(&task_id, |status| status.book_id = Some(book_id))
+            .await;
+        self.spawn_download(
+            task_id.clone(),
+            url,
+            download_type,
+            OnExisting::ReplaceFile,
+            None,
+        )
+        .await;
+
+        Some(task_id)
+    }
+}
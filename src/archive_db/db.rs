@@ -0,0 +1,318 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use anyhow::Result;
+use diesel::connection::Connection as DieselConnection;
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::Text;
+use diesel::sqlite::SqliteConnection;
+use log::info;
+
+use super::models::{Setting, Task, Translation, TranslationBook};
+use super::schema::settings::dsl as settings_dsl;
+use super::schema::tasks::dsl as tasks_dsl;
+use super::schema::translation_books::dsl as translation_books_dsl;
+use super::schema::translations::dsl as translations_dsl;
+use super::{DB_FILENAME, DisplayMode, TaskState, TranslationKind};
+
+const DISPLAY_MODE_KEY: &str = "display_mode";
+
+#[derive(QueryableByName)]
+struct ColumnInfo {
+    #[diesel(sql_type = Text)]
+    name: String,
+}
+
+pub struct ArchiveDb {
+    conn: SqliteConnection,
+}
+
+impl ArchiveDb {
+    pub fn new(path: String) -> Result<Self> {
+        let db_path = PathBuf::from(path).join(DB_FILENAME);
+        let db_path_str = db_path.to_string_lossy();
+
+        info!("Opening or creating archive database at: {db_path_str}");
+
+        let conn = SqliteConnection::establish(&db_path_str)?;
+
+        let mut db = Self { conn };
+        db.ensure_tasks_table_exists()?;
+        db.ensure_translations_table_exists()?;
+        db.ensure_translation_books_table_exists()?;
+        db.ensure_settings_table_exists()?;
+        Ok(db)
+    }
+
+    fn ensure_tasks_table_exists(&mut self) -> Result<()> {
+        sql_query(
+            "CREATE TABLE IF NOT EXISTS tasks (
+                id TEXT PRIMARY KEY,
+                kind TEXT NOT NULL,
+                url TEXT NOT NULL,
+                download_type TEXT,
+                path TEXT,
+                state TEXT NOT NULL,
+                error TEXT,
+                created_at BIGINT NOT NULL,
+                updated_at BIGINT NOT NULL
+            )",
+        )
+        .execute(&mut self.conn)?;
+
+        self.ensure_column_exists("tasks", "gid", "BIGINT")?;
+        self.ensure_column_exists("tasks", "token", "TEXT")?;
+        self.ensure_column_exists("tasks", "started_at", "BIGINT")?;
+        self.ensure_column_exists("tasks", "finished_at", "BIGINT")?;
+        self.ensure_column_exists("tasks", "archive_url", "TEXT")?;
+        self.ensure_column_exists("tasks", "retries", "INTEGER NOT NULL DEFAULT 0")?;
+        self.ensure_column_exists("tasks", "on_existing", "TEXT")?;
+        self.ensure_column_exists("tasks", "book_id", "INTEGER")?;
+
+        Ok(())
+    }
+
+    fn ensure_translations_table_exists(&mut self) -> Result<()> {
+        sql_query(
+            "CREATE TABLE IF NOT EXISTS translations (
+                kind TEXT NOT NULL,
+                raw TEXT NOT NULL,
+                translated TEXT NOT NULL,
+                PRIMARY KEY (kind, raw)
+            )",
+        )
+        .execute(&mut self.conn)?;
+
+        Ok(())
+    }
+
+    /// Several raw names can share a translation, so the books of each are
+    /// kept to tell them apart when reverting.
+    fn ensure_translation_books_table_exists(&mut self) -> Result<()> {
+        sql_query(
+            "CREATE TABLE IF NOT EXISTS translation_books (
+                kind TEXT NOT NULL,
+                raw TEXT NOT NULL,
+                book_id INTEGER NOT NULL,
+                PRIMARY KEY (kind, raw, book_id)
+            )",
+        )
+        .execute(&mut self.conn)?;
+
+        Ok(())
+    }
+
+    fn ensure_settings_table_exists(&mut self) -> Result<()> {
+        sql_query(
+            "CREATE TABLE IF NOT EXISTS settings (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL
+            )",
+        )
+        .execute(&mut self.conn)?;
+
+        Ok(())
+    }
+
+    fn ensure_column_exists(
+        &mut self,
+        table_name: &str,
+        column: &str,
+        definition: &str,
+    ) -> Result<()> {
+        let columns = sql_query(format!("PRAGMA table_info({table_name})"))
+            .load::<ColumnInfo>(&mut self.conn)?;
+
+        if !columns.iter().any(|c| c.name == column) {
+            info!("Adding column {column} to table {table_name}");
+            sql_query(format!(
+                "ALTER TABLE {table_name} ADD COLUMN {column} {definition}"
+            ))
+            .execute(&mut self.conn)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn save_task(&mut self, task: &Task) -> Result<()> {
+        diesel::replace_into(tasks_dsl::tasks)
+            .values(task)
+            .execute(&mut self.conn)?;
+
+        Ok(())
+    }
+
+    pub fn get_unfinished_tasks(&mut self) -> Result<Vec<Task>> {
+        let finished = [
+            TaskState::Done.as_str(),
+            TaskState::Failed.as_str(),
+            TaskState::Cancelled.as_str(),
+        ];
+
+        let result = tasks_dsl::tasks
+            .filter(tasks_dsl::state.ne_all(finished))
+            .order(tasks_dsl::created_at.asc())
+            .load::<Task>(&mut self.conn)?;
+
+        Ok(result)
+    }
+
+    pub fn save_translation(
+        &mut self,
+        kind: TranslationKind,
+        raw: &str,
+        translated: &str,
+    ) -> Result<()> {
+        let translation = Translation {
+            kind: kind.as_str().to_string(),
+            raw: raw.to_string(),
+            translated: translated.to_string(),
+        };
+
+        diesel::insert_into(translations_dsl::translations)
+            .values(&translation)
+            .on_conflict((translations_dsl::kind, translations_dsl::raw))
+            .do_update()
+            .set(translations_dsl::translated.eq(translated))
+            .execute(&mut self.conn)?;
+
+        Ok(())
+    }
+
+    pub fn get_translations(&mut self, kind: TranslationKind) -> Result<Vec<Translation>> {
+        let result = translations_dsl::translations
+            .filter(translations_dsl::kind.eq(kind.as_str()))
+            .load::<Translation>(&mut self.conn)?;
+
+        Ok(result)
+    }
+
+    /// Replaces the translated raw names recorded for a book.
+    pub fn set_book_translations(
+        &mut self,
+        book_id: i32,
+        translations: &[(TranslationKind, String)],
+    ) -> Result<()> {
+        let rows: Vec<_> = translations
+            .iter()
+            .map(|(kind, raw)| TranslationBook {
+                kind: kind.as_str().to_string(),
+                raw: raw.clone(),
+                book_id,
+            })
+            .collect();
+
+        self.conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                diesel::delete(
+                    translation_books_dsl::translation_books
+                        .filter(translation_books_dsl::book_id.eq(book_id)),
+                )
+                .execute(conn)?;
+                diesel::insert_or_ignore_into(translation_books_dsl::translation_books)
+                    .values(&rows)
+                    .execute(conn)?;
+                Ok(())
+            })?;
+
+        Ok(())
+    }
+
+    /// Records that the books show the translation of `raw`.
+    pub fn add_translation_books(
+        &mut self,
+        kind: TranslationKind,
+        raw: &str,
+        book_ids: &[i32],
+    ) -> Result<()> {
+        let rows: Vec<_> = book_ids
+            .iter()
+            .map(|&book_id| TranslationBook {
+                kind: kind.as_str().to_string(),
+                raw: raw.to_string(),
+                book_id,
+            })
+            .collect();
+
+        diesel::insert_or_ignore_into(translation_books_dsl::translation_books)
+            .values(&rows)
+            .execute(&mut self.conn)?;
+
+        Ok(())
+    }
+
+    /// The books of each translated raw name.
+    pub fn get_translation_books(
+        &mut self,
+        kind: TranslationKind,
+    ) -> Result<HashMap<String, HashSet<i32>>> {
+        let rows = translation_books_dsl::translation_books
+            .filter(translation_books_dsl::kind.eq(kind.as_str()))
+            .load::<TranslationBook>(&mut self.conn)?;
+
+        let mut books: HashMap<String, HashSet<i32>> = HashMap::new();
+        for row in rows {
+            books.entry(row.raw).or_default().insert(row.book_id);
+        }
+
+        Ok(books)
+    }
+
+    pub fn get_display_mode(&mut self) -> Result<DisplayMode> {
+        let value = settings_dsl::settings
+            .filter(settings_dsl::key.eq(DISPLAY_MODE_KEY))
+            .select(settings_dsl::value)
+            .first::<String>(&mut self.conn)
+            .optional()?;
+
+        Ok(value
+            .as_deref()
+            .and_then(DisplayMode::parse)
+            .unwrap_or_default())
+    }
+
+    pub fn set_display_mode(&mut self, mode: DisplayMode) -> Result<()> {
+        let setting = Setting {
+            key: DISPLAY_MODE_KEY.to_string(),
+            value: mode.as_str().to_string(),
+        };
+
+        diesel::insert_into(settings_dsl::settings)
+            .values(&setting)
+            .on_conflict(settings_dsl::key)
+            .do_update()
+            .set(settings_dsl::value.eq(&setting.value))
+            .execute(&mut self.conn)?;
+
+        Ok(())
+    }
+
+    /// Deletes the finished tasks older than the retention window.
+    pub fn delete_finished_tasks_before(&mut self, before: i64) -> Result<usize> {
+        let finished = [
+            TaskState::Done.as_str(),
+            TaskState::Failed.as_str(),
+            TaskState::Cancelled.as_str(),
+        ];
+
+        let deleted = diesel::delete(
+            tasks_dsl::tasks
+                .filter(tasks_dsl::state.eq_any(finished))
+                .filter(tasks_dsl::finished_at.lt(before)),
+        )
+        .execute(&mut self.conn)?;
+
+        Ok(deleted)
+    }
+
+    pub fn get_failed_tasks_since(&mut self, since: i64) -> Result<Vec<Task>> {
+        let result = tasks_dsl::tasks
+            .filter(tasks_dsl::state.eq(TaskState::Failed.as_str()))
+            .filter(tasks_dsl::finished_at.ge(since))
+            .order(tasks_dsl::created_at.asc())
+            .load::<Task>(&mut self.conn)?;
+
+        Ok(result)
+    }
+}
@@ -0,0 +1,185 @@
+pub mod db;
+mod models;
+mod schema;
+
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::utils::mapping::MappingProfile;
+
+pub use models::Task;
+
+const DB_FILENAME: &str = "eh_archive.db";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskKind {
+    Download,
+    Import,
+    MetadataReplace,
+    MetadataUpdate,
+    MetadataRevert,
+    Upgrade,
+    VersionCheck,
+}
+
+impl TaskKind {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Download => "download",
+            Self::Import => "import",
+            Self::MetadataReplace => "metadata_replace",
+            Self::MetadataUpdate => "metadata_update",
+            Self::MetadataRevert => "metadata_revert",
+            Self::Upgrade => "upgrade",
+            Self::VersionCheck => "version_check",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "download" => Some(Self::Download),
+            "import" => Some(Self::Import),
+            "metadata_replace" => Some(Self::MetadataReplace),
+            "metadata_update" => Some(Self::MetadataUpdate),
+            "metadata_revert" => Some(Self::MetadataRevert),
+            "upgrade" => Some(Self::Upgrade),
+            "version_check" => Some(Self::VersionCheck),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Queued,
+    FetchingMetadata,
+    Downloading,
+    Writing,
+    ExtractingCover,
+    AddingToCalibre,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl TaskState {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::FetchingMetadata => "fetching_metadata",
+            Self::Downloading => "downloading",
+            Self::Writing => "writing",
+            Self::ExtractingCover => "extracting_cover",
+            Self::AddingToCalibre => "adding_to_calibre",
+            Self::Done => "done",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "queued" => Some(Self::Queued),
+            "fetching_metadata" => Some(Self::FetchingMetadata),
+            "downloading" => Some(Self::Downloading),
+            "writing" => Some(Self::Writing),
+            "extracting_cover" => Some(Self::ExtractingCover),
+            "adding_to_calibre" => Some(Self::AddingToCalibre),
+            "done" => Some(Self::Done),
+            "failed" => Some(Self::Failed),
+            "cancelled" => Some(Self::Cancelled),
+            _ => None,
+        }
+    }
+
+    pub const fn is_finished(&self) -> bool {
+        matches!(self, Self::Done | Self::Failed | Self::Cancelled)
+    }
+}
+
+impl Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The calibre fields whose names EhArchive translates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TranslationKind {
+    Author,
+    Publisher,
+    Tag,
+}
+
+impl TranslationKind {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Author => "author",
+            Self::Publisher => "publisher",
+            Self::Tag => "tag",
+        }
+    }
+}
+
+/// How translated names are shown in the calibre library.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisplayMode {
+    Raw,
+    #[default]
+    Translated,
+    TranslatedRaw,
+}
+
+impl DisplayMode {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Raw => "raw",
+            Self::Translated => "translated",
+            Self::TranslatedRaw => "translated_raw",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "raw" => Some(Self::Raw),
+            "translated" => Some(Self::Translated),
+            "translated_raw" => Some(Self::TranslatedRaw),
+            _ => None,
+        }
+    }
+
+    /// Tags keep their translated namespace, the raw part only repeats the
+    /// raw tag, e.g. `女性:巨乳 (big breasts)` with the default tag template.
+    pub fn render(
+        &self,
+        kind: TranslationKind,
+        raw: &str,
+        translated: &str,
+        mapping: &MappingProfile,
+    ) -> String {
+        match self {
+            Self::Raw => raw.to_string(),
+            Self::Translated => translated.to_string(),
+            Self::TranslatedRaw => {
+                let tag = match kind {
+                    TranslationKind::Tag => {
+                        mapping.parse_tag(raw).zip(mapping.parse_tag(translated))
+                    }
+                    _ => None,
+                };
+                match tag {
+                    Some(((_, raw_name), (_, name))) if raw_name == name => translated.to_string(),
+                    Some(((_, raw_name), (namespace, name))) => {
+                        mapping.format_tag(namespace, &format!("{name} ({raw_name})"))
+                    }
+                    None if raw == translated => translated.to_string(),
+                    None => format!("{translated} ({raw})"),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use diesel::prelude::*;
+
+use super::schema::*;
+
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[diesel(table_name = tasks)]
+pub struct Task {
+    pub id: String,
+    pub kind: String,
+    pub url: String,
+    pub download_type: Option<String>,
+    pub path: Option<String>,
+    pub state: String,
+    pub error: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub gid: Option<i64>,
+    pub token: Option<String>,
+    pub started_at: Option<i64>,
+    pub finished_at: Option<i64>,
+    pub archive_url: Option<String>,
+    pub retries: i32,
+    pub on_existing: Option<String>,
+    pub book_id: Option<i32>,
+}
+
+/// A raw calibre name and the translation EhArchive applied to it.
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[diesel(table_name = translations)]
+pub struct Translation {
+    pub kind: String,
+    pub raw: String,
+    pub translated: String,
+}
+
+/// A book whose calibre entry shows the translation of a raw name.
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[diesel(table_name = translation_books)]
+pub struct TranslationBook {
+    pub kind: String,
+    pub raw: String,
+    pub book_id: i32,
+}
+
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[diesel(table_name = settings)]
+pub struct Setting {
+    pub key: String,
+    pub value: String,
+}
@@ -0,0 +1,46 @@
+diesel::table! {
+    tasks (id) {
+        id -> Text,
+        kind -> Text,
+        url -> Text,
+        download_type -> Nullable<Text>,
+        path -> Nullable<Text>,
+        state -> Text,
+        error -> Nullable<Text>,
+        created_at -> BigInt,
+        updated_at -> BigInt,
+        gid -> Nullable<BigInt>,
+        token -> Nullable<Text>,
+        started_at -> Nullable<BigInt>,
+        finished_at -> Nullable<BigInt>,
+        archive_url -> Nullable<Text>,
+        retries -> Integer,
+        on_existing -> Nullable<Text>,
+        book_id -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    translations (kind, raw) {
+        kind -> Text,
+        raw -> Text,
+        translated -> Text,
+    }
+}
+
+diesel::table! {
+    translation_books (kind, raw, book_id) {
+        kind -> Text,
+        raw -> Text,
+        book_id -> Integer,
+    }
+}
+
+diesel::table! {
+    settings (key) {
+        key -> Text,
+        value -> Text,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(tasks, translations, translation_books, settings);
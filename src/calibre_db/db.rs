@@ -0,0 +1,504 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use anyhow::{Result, anyhow};
+use chrono::Utc;
+use diesel::connection::{Connection as DieselConnection, SimpleConnection};
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Bool, Double, Integer, Text};
+use diesel::sqlite::SqliteConnection;
+use log::info;
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use super::{ColumnType, ColumnValue};
+use crate::archive_db::TranslationKind;
+
+static TITLE_ARTICLE_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)^(a|an|the)\s+(.+)$").unwrap());
+
+// calibre's triggers call these, so they have to exist on every connection
+// that writes to the library
+define_sql_function!(fn title_sort(title: Text) -> Text);
+define_sql_function!(fn uuid4() -> Text);
+
+#[derive(QueryableByName)]
+struct BookIdentifier {
+    #[diesel(sql_type = Integer)]
+    book: i32,
+    #[diesel(sql_type = Text)]
+    val: String,
+}
+
+#[derive(QueryableByName)]
+struct CustomColumnRow {
+    #[diesel(sql_type = Integer)]
+    id: i32,
+    #[diesel(sql_type = Text)]
+    datatype: String,
+}
+
+#[derive(QueryableByName)]
+struct CommentsRow {
+    #[diesel(sql_type = Text)]
+    text: String,
+}
+
+#[derive(QueryableByName)]
+struct LinkedBook {
+    #[diesel(sql_type = Integer)]
+    book: i32,
+}
+
+#[derive(QueryableByName)]
+struct RowId {
+    #[diesel(sql_type = Integer)]
+    id: i32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CustomColumn {
+    id: i32,
+    datatype: ColumnType,
+}
+
+#[derive(QueryableByName)]
+struct BookFile {
+    #[diesel(sql_type = Text)]
+    path: String,
+    #[diesel(sql_type = Text)]
+    name: String,
+}
+
+/// Direct access to the calibre `metadata.db`, for the few things the
+/// calibre client does not cover.
+pub struct CalibreDb {
+    conn: SqliteConnection,
+    library_root: PathBuf,
+    custom_columns: HashMap<String, CustomColumn>,
+}
+
+impl CalibreDb {
+    pub fn new(library_root: String, db_path: &str) -> Result<Self> {
+        info!("Opening calibre database at: {db_path}");
+
+        let mut conn = SqliteConnection::establish(db_path)?;
+        // calibre keeps the default rollback journal, and the libcalibre
+        // connection may hold the write lock for a while
+        conn.batch_execute("PRAGMA busy_timeout = 10000; PRAGMA journal_mode = DELETE;")?;
+        title_sort_utils::register_impl(&mut conn, |title: String| sort_title(&title))?;
+        uuid4_utils::register_nondeterministic_impl(&conn, || uuid::Uuid::new_v4().to_string())?;
+
+        Ok(Self {
+            conn,
+            library_root: PathBuf::from(library_root),
+            custom_columns: HashMap::new(),
+        })
+    }
+
+    /// Returns the directory of the book and the path of its file in the given
+    /// format, e.g. `CBZ`.
+    pub fn get_book_file(
+        &mut self,
+        book_id: i32,
+        format: &str,
+    ) -> Result<Option<(PathBuf, PathBuf)>> {
+        let file = sql_query(
+            "SELECT books.path AS path, data.name AS name
+            FROM books JOIN data ON data.book = books.id
+            WHERE books.id = ? AND data.format = ?",
+        )
+        .bind::<Integer, _>(book_id)
+        .bind::<Text, _>(format)
+        .get_result::<BookFile>(&mut self.conn)
+        .optional()?;
+
+        Ok(file.map(|file| {
+            let book_dir = self.library_root.join(file.path);
+            let file_path = book_dir.join(format!("{}.{}", file.name, format.to_lowercase()));
+            (book_dir, file_path)
+        }))
+    }
+
+    pub fn get_identifiers(&mut self, label: &str) -> Result<Vec<(i32, String)>> {
+        let identifiers = sql_query("SELECT book, val FROM identifiers WHERE type = ?")
+            .bind::<Text, _>(label)
+            .load::<BookIdentifier>(&mut self.conn)?;
+
+        Ok(identifiers.into_iter().map(|i| (i.book, i.val)).collect())
+    }
+
+    /// Records a replaced file of the book, and its new cover if it got one,
+    /// so calibre reloads both.
+    pub fn set_book_file_replaced(
+        &mut self,
+        book_id: i32,
+        format: &str,
+        size: i64,
+        has_cover: bool,
+    ) -> Result<()> {
+        let last_modified = Utc::now().format("%Y-%m-%d %H:%M:%S%.6f%:z").to_string();
+        self.conn.transaction(|conn| {
+            sql_query("UPDATE data SET uncompressed_size = ? WHERE book = ? AND format = ?")
+                .bind::<BigInt, _>(size)
+                .bind::<Integer, _>(book_id)
+                .bind::<Text, _>(format)
+                .execute(conn)?;
+            sql_query(
+                "UPDATE books SET has_cover = has_cover OR ?, last_modified = ? WHERE id = ?",
+            )
+            .bind::<Bool, _>(has_cover)
+            .bind::<Text, _>(last_modified)
+            .bind::<Integer, _>(book_id)
+            .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    /// Links the book to the series, creating the series if needed.
+    pub fn set_book_series(&mut self, book_id: i32, name: &str, index: f32) -> Result<()> {
+        self.conn.transaction(|conn| {
+            sql_query("INSERT OR IGNORE INTO series (name) VALUES (?)")
+                .bind::<Text, _>(name)
+                .execute(conn)?;
+            let series_id = sql_query("SELECT id FROM series WHERE name = ?")
+                .bind::<Text, _>(name)
+                .get_result::<RowId>(conn)?
+                .id;
+            sql_query("DELETE FROM books_series_link WHERE book = ?")
+                .bind::<Integer, _>(book_id)
+                .execute(conn)?;
+            sql_query("INSERT INTO books_series_link (book, series) VALUES (?, ?)")
+                .bind::<Integer, _>(book_id)
+                .bind::<Integer, _>(series_id)
+                .execute(conn)?;
+            sql_query("UPDATE books SET series_index = ? WHERE id = ?")
+                .bind::<Double, _>(index as f64)
+                .bind::<Integer, _>(book_id)
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+
+    pub fn get_book_comments(&mut self, book_id: i32) -> Result<Option<String>> {
+        let comments = sql_query("SELECT text FROM comments WHERE book = ?")
+            .bind::<Integer, _>(book_id)
+            .get_result::<CommentsRow>(&mut self.conn)
+            .optional()?;
+
+        Ok(comments.map(|row| row.text))
+    }
+
+    pub fn set_book_comments(&mut self, book_id: i32, comments: &str) -> Result<()> {
+        sql_query(
+            "INSERT INTO comments (book, text) VALUES (?, ?)
+            ON CONFLICT(book) DO UPDATE SET text = excluded.text",
+        )
+        .bind::<Integer, _>(book_id)
+        .bind::<Text, _>(comments)
+        .execute(&mut self.conn)?;
+
+        Ok(())
+    }
+
+    pub fn get_linked_books(&mut self, kind: TranslationKind, item_id: i32) -> Result<Vec<i32>> {
+        let (_, link_table, column) = link_tables(kind);
+        let books = sql_query(format!("SELECT book FROM {link_table} WHERE {column} = ?"))
+            .bind::<Integer, _>(item_id)
+            .load::<LinkedBook>(&mut self.conn)?;
+
+        Ok(books.into_iter().map(|b| b.book).collect())
+    }
+
+    /// Links the books to the author, publisher or tag called `name` instead
+    /// of `item_id`, creating it if needed.
+    pub fn relink_books(
+        &mut self,
+        kind: TranslationKind,
+        item_id: i32,
+        book_ids: &[i32],
+        name: &str,
+    ) -> Result<()> {
+        let (table, link_table, column) = link_tables(kind);
+        self.conn.transaction::<_, diesel::result::Error, _>(|conn| {
+            let existing = sql_query(format!("SELECT id FROM {table} WHERE name = ?"))
+                .bind::<Text, _>(name)
+                .get_result::<RowId>(conn)
+                .optional()?;
+            let target_id = match existing {
+                Some(row) => row.id,
+                None => {
+                    let insert = match kind {
+                        TranslationKind::Tag => "INSERT INTO tags (name) VALUES (?1)",
+                        TranslationKind::Author => "INSERT INTO authors (name, sort) VALUES (?1, ?1)",
+                        TranslationKind::Publisher => {
+                            "INSERT INTO publishers (name, sort) VALUES (?1, ?1)"
+                        }
+                    };
+                    sql_query(insert).bind::<Text, _>(name).execute(conn)?;
+                    sql_query("SELECT last_insert_rowid() AS id")
+                        .get_result::<RowId>(conn)?
+                        .id
+                }
+            };
+
+            for &book_id in book_ids {
+                // A book already linked to the target only loses the old link
+                sql_query(format!(
+                    "UPDATE OR IGNORE {link_table} SET {column} = ?1 WHERE book = ?2 AND {column} = ?3"
+                ))
+                .bind::<Integer, _>(target_id)
+                .bind::<Integer, _>(book_id)
+                .bind::<Integer, _>(item_id)
+                .execute(conn)?;
+                sql_query(format!(
+                    "DELETE FROM {link_table} WHERE book = ? AND {column} = ?"
+                ))
+                .bind::<Integer, _>(book_id)
+                .bind::<Integer, _>(item_id)
+                .execute(conn)?;
+            }
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    /// Looks up the custom column with the label, creating it the way
+    /// calibre does if it is missing, and returns its datatype.
+    pub fn ensure_custom_column(
+        &mut self,
+        label: &str,
+        name: &str,
+        datatype: ColumnType,
+    ) -> Result<ColumnType> {
+        if !label
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+            || !label.starts_with(|c: char| c.is_ascii_lowercase())
+        {
+            return Err(anyhow!("Invalid custom column label: {label}"));
+        }
+
+        let existing = sql_query("SELECT id, datatype FROM custom_columns WHERE label = ?")
+            .bind::<Text, _>(label)
+            .get_result::<CustomColumnRow>(&mut self.conn)
+            .optional()?;
+
+        let column = match existing {
+            Some(row) => CustomColumn {
+                id: row.id,
+                datatype: ColumnType::parse(&row.datatype).ok_or_else(|| {
+                    anyhow!(
+                        "Unsupported datatype of custom column #{label}: {}",
+                        row.datatype
+                    )
+                })?,
+            },
+            None => {
+                info!(
+                    "Creating calibre custom column #{label} ({})",
+                    datatype.as_str()
+                );
+                let id = self.create_custom_column(label, name, datatype)?;
+                CustomColumn { id, datatype }
+            }
+        };
+        self.custom_columns.insert(label.to_string(), column);
+
+        Ok(column.datatype)
+    }
+
+    /// Creates the tables, indexes and foreign key triggers calibre's own
+    /// `create_custom_column` does.
+    fn create_custom_column(
+        &mut self,
+        label: &str,
+        name: &str,
+        datatype: ColumnType,
+    ) -> Result<i32> {
+        self.conn.transaction(|conn| {
+            sql_query(
+                "INSERT INTO custom_columns (label, name, datatype, is_multiple, editable, display, normalized)
+                VALUES (?, ?, ?, 0, 1, '{}', ?)",
+            )
+            .bind::<Text, _>(label)
+            .bind::<Text, _>(name)
+            .bind::<Text, _>(datatype.as_str())
+            .bind::<Bool, _>(datatype.is_normalized())
+            .execute(conn)?;
+            let id = sql_query("SELECT id FROM custom_columns WHERE label = ?")
+                .bind::<Text, _>(label)
+                .get_result::<RowId>(conn)?
+                .id;
+
+            let table = format!("custom_column_{id}");
+            let collate = if datatype.sql_type() == "TEXT" {
+                "COLLATE NOCASE"
+            } else {
+                ""
+            };
+            let script = if datatype.is_normalized() {
+                let link_table = format!("books_{table}_link");
+                format!(
+                    "CREATE TABLE {table} (
+                        id INTEGER PRIMARY KEY AUTOINCREMENT,
+                        value TEXT NOT NULL {collate},
+                        link TEXT NOT NULL DEFAULT '',
+                        UNIQUE(value)
+                    );
+                    CREATE INDEX {table}_idx ON {table} (value COLLATE NOCASE);
+                    CREATE TABLE {link_table} (
+                        id INTEGER PRIMARY KEY AUTOINCREMENT,
+                        book INTEGER NOT NULL,
+                        value INTEGER NOT NULL,
+                        UNIQUE(book, value)
+                    );
+                    CREATE INDEX {link_table}_aidx ON {link_table} (value);
+                    CREATE INDEX {link_table}_bidx ON {link_table} (book);
+                    CREATE TRIGGER fkc_update_{link_table}_a
+                    BEFORE UPDATE OF book ON {link_table}
+                    BEGIN
+                        SELECT CASE
+                            WHEN (SELECT id FROM books WHERE id = NEW.book) IS NULL
+                            THEN RAISE(ABORT, 'Foreign key violation: book not in books')
+                        END;
+                    END;
+                    CREATE TRIGGER fkc_update_{link_table}_b
+                    BEFORE UPDATE OF value ON {link_table}
+                    BEGIN
+                        SELECT CASE
+                            WHEN (SELECT id FROM {table} WHERE id = NEW.value) IS NULL
+                            THEN RAISE(ABORT, 'Foreign key violation: value not in {table}')
+                        END;
+                    END;
+                    CREATE TRIGGER fkc_insert_{link_table}
+                    BEFORE INSERT ON {link_table}
+                    BEGIN
+                        SELECT CASE
+                            WHEN (SELECT id FROM books WHERE id = NEW.book) IS NULL
+                            THEN RAISE(ABORT, 'Foreign key violation: book not in books')
+                            WHEN (SELECT id FROM {table} WHERE id = NEW.value) IS NULL
+                            THEN RAISE(ABORT, 'Foreign key violation: value not in {table}')
+                        END;
+                    END;
+                    CREATE TRIGGER fkc_delete_{link_table}
+                    AFTER DELETE ON {table}
+                    BEGIN
+                        DELETE FROM {link_table} WHERE value = OLD.id;
+                    END;"
+                )
+            } else {
+                format!(
+                    "CREATE TABLE {table} (
+                        id INTEGER PRIMARY KEY AUTOINCREMENT,
+                        book INTEGER,
+                        value {} NOT NULL {collate},
+                        UNIQUE(book)
+                    );
+                    CREATE INDEX {table}_idx ON {table} (book);
+                    CREATE TRIGGER fkc_insert_{table}
+                    BEFORE INSERT ON {table}
+                    BEGIN
+                        SELECT CASE
+                            WHEN (SELECT id FROM books WHERE id = NEW.book) IS NULL
+                            THEN RAISE(ABORT, 'Foreign key violation: book not in books')
+                        END;
+                    END;
+                    CREATE TRIGGER fkc_update_{table}
+                    BEFORE UPDATE OF book ON {table}
+                    BEGIN
+                        SELECT CASE
+                            WHEN (SELECT id FROM books WHERE id = NEW.book) IS NULL
+                            THEN RAISE(ABORT, 'Foreign key violation: book not in books')
+                        END;
+                    END;",
+                    datatype.sql_type()
+                )
+            };
+            conn.batch_execute(&script)?;
+
+            Ok(id)
+        })
+    }
+
+    /// Writes the value to a column set up by `ensure_custom_column`.
+    pub fn set_custom_column(
+        &mut self,
+        book_id: i32,
+        label: &str,
+        value: ColumnValue,
+    ) -> Result<()> {
+        let column = *self
+            .custom_columns
+            .get(label)
+            .ok_or_else(|| anyhow!("Unknown custom column: #{label}"))?;
+        let value = value.clone().convert(column.datatype).ok_or_else(|| {
+            anyhow!(
+                "Cannot write {value:?} to {} column #{label}",
+                column.datatype.as_str()
+            )
+        })?;
+        let table = format!("custom_column_{}", column.id);
+
+        if column.datatype.is_normalized() {
+            let link_table = format!("books_{table}_link");
+            return self.conn.transaction(|conn| {
+                let value = value.to_string();
+                sql_query(format!("INSERT OR IGNORE INTO {table} (value) VALUES (?)"))
+                    .bind::<Text, _>(&value)
+                    .execute(conn)?;
+                let value_id = sql_query(format!("SELECT id FROM {table} WHERE value = ?"))
+                    .bind::<Text, _>(&value)
+                    .get_result::<RowId>(conn)?
+                    .id;
+                sql_query(format!("DELETE FROM {link_table} WHERE book = ?"))
+                    .bind::<Integer, _>(book_id)
+                    .execute(conn)?;
+                sql_query(format!(
+                    "INSERT INTO {link_table} (book, value) VALUES (?, ?)"
+                ))
+                .bind::<Integer, _>(book_id)
+                .bind::<Integer, _>(value_id)
+                .execute(conn)?;
+                Ok(())
+            });
+        }
+
+        let query = sql_query(format!(
+            "INSERT INTO {table} (book, value) VALUES (?, ?)
+            ON CONFLICT(book) DO UPDATE SET value = excluded.value"
+        ))
+        .bind::<Integer, _>(book_id);
+        match value {
+            ColumnValue::Int(v) => query.bind::<BigInt, _>(v).execute(&mut self.conn)?,
+            ColumnValue::Float(v) => query.bind::<Double, _>(v).execute(&mut self.conn)?,
+            ColumnValue::Bool(v) => query.bind::<Bool, _>(v).execute(&mut self.conn)?,
+            ColumnValue::Datetime(v) => query
+                .bind::<Text, _>(v.format("%Y-%m-%d %H:%M:%S%:z").to_string())
+                .execute(&mut self.conn)?,
+            ColumnValue::Text(v) => query.bind::<Text, _>(v).execute(&mut self.conn)?,
+        };
+
+        Ok(())
+    }
+}
+
+/// The item table, link table and link column of calibre names.
+fn link_tables(kind: TranslationKind) -> (&'static str, &'static str, &'static str) {
+    match kind {
+        TranslationKind::Author => ("authors", "books_authors_link", "author"),
+        TranslationKind::Publisher => ("publishers", "books_publishers_link", "publisher"),
+        TranslationKind::Tag => ("tags", "books_tags_link", "tag"),
+    }
+}
+
+/// Moves a leading English article to the end, as calibre sorts titles.
+fn sort_title(title: &str) -> String {
+    match TITLE_ARTICLE_REGEX.captures(title) {
+        Some(c) => format!("{}, {}", &c[2], &c[1]),
+        None => title.to_string(),
+    }
+}
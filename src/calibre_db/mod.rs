@@ -0,0 +1,117 @@
+use std::fmt::{self, Display};
+
+use chrono::{DateTime, Utc};
+use libcalibre::client::CalibreClient;
+use serde::Deserialize;
+
+use db::CalibreDb;
+
+pub mod db;
+
+/// The calibre library, written both through libcalibre and directly. They
+/// share one lock so their writes to `metadata.db` never interleave.
+pub struct Calibre {
+    pub client: CalibreClient,
+    pub db: CalibreDb,
+}
+
+/// Datatypes of calibre custom columns that can be written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColumnType {
+    Int,
+    Float,
+    Datetime,
+    Bool,
+    Comments,
+    Text,
+}
+
+impl ColumnType {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Int => "int",
+            Self::Float => "float",
+            Self::Datetime => "datetime",
+            Self::Bool => "bool",
+            Self::Comments => "comments",
+            Self::Text => "text",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "int" => Some(Self::Int),
+            "float" => Some(Self::Float),
+            "datetime" => Some(Self::Datetime),
+            "bool" => Some(Self::Bool),
+            "comments" => Some(Self::Comments),
+            "text" => Some(Self::Text),
+            _ => None,
+        }
+    }
+
+    const fn sql_type(&self) -> &'static str {
+        match self {
+            Self::Int => "INT",
+            Self::Float => "REAL",
+            Self::Datetime => "timestamp",
+            Self::Bool => "BOOL",
+            Self::Comments | Self::Text => "TEXT",
+        }
+    }
+
+    /// Text columns keep their values in a separate table, like tags.
+    const fn is_normalized(&self) -> bool {
+        matches!(self, Self::Text)
+    }
+
+    /// Whether values of the other type can be written to this column.
+    pub fn accepts(&self, other: ColumnType) -> bool {
+        self == &other
+            || matches!(self, Self::Comments | Self::Text)
+            || matches!(
+                (self, other),
+                (Self::Float, Self::Int) | (Self::Int, Self::Bool)
+            )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnValue {
+    Int(i64),
+    Float(f64),
+    Datetime(DateTime<Utc>),
+    Bool(bool),
+    Text(String),
+}
+
+impl ColumnValue {
+    /// Converts the value for a column of the given type, see
+    /// `ColumnType::accepts`.
+    pub fn convert(self, datatype: ColumnType) -> Option<Self> {
+        match (datatype, self) {
+            (ColumnType::Comments | ColumnType::Text, Self::Text(v)) => Some(Self::Text(v)),
+            (ColumnType::Comments | ColumnType::Text, v) => Some(Self::Text(v.to_string())),
+            (ColumnType::Int, Self::Int(v)) => Some(Self::Int(v)),
+            (ColumnType::Int, Self::Bool(v)) => Some(Self::Int(v as i64)),
+            (ColumnType::Float, Self::Int(v)) => Some(Self::Float(v as f64)),
+            (ColumnType::Float, Self::Float(v)) => Some(Self::Float(v)),
+            (ColumnType::Datetime, Self::Datetime(v)) => Some(Self::Datetime(v)),
+            (ColumnType::Bool, Self::Bool(v)) => Some(Self::Bool(v)),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ColumnValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Int(v) => write!(f, "{v}"),
+            Self::Float(v) => write!(f, "{v}"),
+            Self::Datetime(v) => write!(f, "{}", v.format("%Y-%m-%d %H:%M:%S")),
+            Self::Bool(v) => write!(f, "{v}"),
+            Self::Text(v) => write!(f, "{v}"),
+        }
+    }
+}
@@ -1,6 +1,15 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
 use clap::Parser;
 use libeh::dto::site::Site;
 
+use crate::api::{
+    DownloadType,
+    utils::{TagPrefixes, mapping::MappingProfile, retry::RetryPolicy},
+};
+use crate::tag_db::TagDbSource;
+
 #[derive(Debug, Parser)]
 pub struct Config {
     #[clap(env = "EH_AUTH_ID")]
@@ -20,9 +29,36 @@ pub struct Config {
     library_root: String,
     #[clap(long, env = "TAG_DB_ROOT")]
     tag_db_root: String,
+    #[clap(long, env = "ARCHIVE_DB_ROOT")]
+    archive_db_root: Option<String>,
 
     #[clap(long, env = "LIMIT", default_value = "5")]
     limit: usize,
+    #[clap(long, env = "FAILED_TASK_RETENTION", default_value = "86400")]
+    failed_task_retention: i64,
+    #[clap(long, env = "METADATA_RETRY", default_value = "3,1000,500")]
+    metadata_retry: RetryPolicy,
+    #[clap(long, env = "DOWNLOAD_RETRY", default_value = "5,5000,2000")]
+    download_retry: RetryPolicy,
+
+    #[clap(long, env = "TAG_DB_SOURCE", default_value = "github")]
+    tag_db_source: TagDbSource,
+    #[clap(long, env = "TAG_REFRESH_INTERVAL", default_value = "86400")]
+    tag_refresh_interval: u64,
+    #[clap(long, env = "TAG_REFRESH_UPDATE_METADATA")]
+    tag_refresh_update_metadata: bool,
+    #[clap(long, env = "VERSION_CHECK_INTERVAL", default_value = "0")]
+    version_check_interval: u64,
+    #[clap(long, env = "VERSION_UPGRADE")]
+    version_upgrade: Option<DownloadType>,
+    #[clap(long, env = "UNTAGGED_TAG_PREFIX", default_value = "misc")]
+    untagged_tag_prefix: String,
+    #[clap(long, env = "TEMP_TAG_PREFIX", default_value = "temp")]
+    temp_tag_prefix: String,
+    #[clap(long, env = "TAG_ALIAS_FILE")]
+    tag_alias_file: Option<PathBuf>,
+    #[clap(long, env = "MAPPING_PROFILE")]
+    mapping_profile: Option<PathBuf>,
 }
 
 impl Config {
@@ -62,7 +98,59 @@ impl Config {
         &self.tag_db_root
     }
 
+    pub fn archive_db_path(&self) -> &str {
+        self.archive_db_root.as_deref().unwrap_or(&self.tag_db_root)
+    }
+
     pub const fn limit(&self) -> usize {
         self.limit
     }
+
+    pub const fn failed_task_retention(&self) -> i64 {
+        self.failed_task_retention
+    }
+
+    pub const fn metadata_retry(&self) -> RetryPolicy {
+        self.metadata_retry
+    }
+
+    pub const fn download_retry(&self) -> RetryPolicy {
+        self.download_retry
+    }
+
+    pub fn tag_db_source(&self) -> TagDbSource {
+        self.tag_db_source.clone()
+    }
+
+    pub const fn tag_refresh_interval(&self) -> u64 {
+        self.tag_refresh_interval
+    }
+
+    pub const fn tag_refresh_update_metadata(&self) -> bool {
+        self.tag_refresh_update_metadata
+    }
+
+    pub const fn version_check_interval(&self) -> u64 {
+        self.version_check_interval
+    }
+
+    pub const fn version_upgrade(&self) -> Option<DownloadType> {
+        self.version_upgrade
+    }
+
+    pub fn tag_alias_file(&self) -> Option<PathBuf> {
+        self.tag_alias_file.clone()
+    }
+
+    pub fn mapping_profile(&self) -> Result<MappingProfile> {
+        let mut profile = match &self.mapping_profile {
+            Some(path) => MappingProfile::load(path)?,
+            None => MappingProfile::default(),
+        };
+        profile.tag_prefixes = TagPrefixes {
+            untagged: self.untagged_tag_prefix.clone(),
+            temp: self.temp_tag_prefix.clone(),
+        };
+        Ok(profile)
+    }
 }
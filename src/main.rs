@@ -1,40 +1,66 @@
 mod api;
+mod archive_db;
+mod calibre_db;
 mod config;
 mod g_log;
 mod tag_db;
 
-use std::{collections::HashSet, path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
 use axum::{
     Router,
-    routing::{get, post},
+    routing::{delete, get, post},
 };
 use libcalibre::{client::CalibreClient, util::get_db_path};
 use libeh::{
     client::{auth::EhClientAuth, client::EhClient, config::EhClientConfig},
     dto::site::Site,
 };
-use tokio::sync::{Mutex, Semaphore};
+use tokio::sync::{Mutex, Semaphore, broadcast};
 
 use api::{
-    calibre::{handle_book_metadata_replace, handle_metadata_update},
-    download::handle_download,
+    TaskEvent, TaskStatus, VersionReport,
+    calibre::{handle_book_metadata_replace, handle_metadata_revert, handle_metadata_update},
+    download::{handle_batch_download, handle_download},
+    events::handle_events,
     import::handle_import,
-    tag_query::handle_tag_query,
-    tasks::get_active_tasks,
+    tag_overrides::{
+        handle_tag_override_delete, handle_tag_override_list, handle_tag_override_set,
+    },
+    tag_query::{handle_tag_query, handle_tag_query_batch, handle_tag_search},
+    tasks::{get_active_tasks, handle_task_cancel},
+    utils::{
+        archive::ArchiveClient, calibre::ensure_custom_columns, mapping::MappingProfile,
+        retry::RetryPolicy,
+    },
+    versions::{handle_version_check, handle_version_report},
 };
+use archive_db::db::ArchiveDb;
+use calibre_db::{Calibre, db::CalibreDb};
 use config::Config;
-use tag_db::db::EhTagDb;
+use tag_db::{TagDbSource, db::EhTagDb};
+
+const EVENT_CAPACITY: usize = 256;
 
 #[derive(Clone)]
 struct DownloadManager {
     client: EhClient,
+    archive_client: ArchiveClient,
     is_exhentai: bool,
     output: PathBuf,
     semaphore: Arc<Semaphore>,
     tag_db: Arc<Mutex<EhTagDb>>,
-    calibre_client: Arc<Mutex<CalibreClient>>,
-    active_tasks: Arc<Mutex<HashSet<String>>>,
+    tag_db_source: TagDbSource,
+    mapping: Arc<MappingProfile>,
+    tag_alias_file: Option<PathBuf>,
+    calibre: Arc<Mutex<Calibre>>,
+    archive_db: Arc<Mutex<ArchiveDb>>,
+    active_tasks: Arc<Mutex<HashMap<String, TaskStatus>>>,
+    events: broadcast::Sender<TaskEvent>,
+    failed_task_retention: i64,
+    metadata_retry: RetryPolicy,
+    download_retry: RetryPolicy,
+    version_report: Arc<Mutex<Option<VersionReport>>>,
 }
 
 impl DownloadManager {
@@ -45,28 +71,47 @@ impl DownloadManager {
             igneous: config.igneous().map(|s| s.into()),
         };
         let site = config.site();
+        let is_exhentai = matches!(site, Site::Ex);
+        let archive_client = ArchiveClient::new(&eh_auth_config, is_exhentai).unwrap();
         let eh_client_config = EhClientConfig {
             site,
             proxy: None,
             auth: Some(eh_auth_config),
         };
         let tag_db = EhTagDb::new(config.tag_db_path().into()).unwrap();
-        let valid_path = get_db_path(config.library_root());
-        let calibre_client = CalibreClient::new(valid_path.unwrap());
+        let archive_db = ArchiveDb::new(config.archive_db_path().into()).unwrap();
+        let valid_path = get_db_path(config.library_root()).unwrap();
+        let mapping = config.mapping_profile().unwrap();
+        let mut calibre_db = CalibreDb::new(config.library_root().into(), &valid_path).unwrap();
+        ensure_custom_columns(&mut calibre_db, &mapping).unwrap();
+        let calibre_client = CalibreClient::new(valid_path);
         Self {
             client: EhClient::new(eh_client_config),
-            is_exhentai: matches!(site, Site::Ex),
+            archive_client,
+            is_exhentai,
             output: config.archive_output().into(),
             semaphore: Arc::new(Semaphore::new(config.limit())),
             tag_db: Arc::new(Mutex::new(tag_db)),
-            calibre_client: Arc::new(Mutex::new(calibre_client)),
-            active_tasks: Arc::new(Mutex::new(HashSet::new())),
+            tag_db_source: config.tag_db_source(),
+            mapping: Arc::new(mapping),
+            tag_alias_file: config.tag_alias_file(),
+            calibre: Arc::new(Mutex::new(Calibre {
+                client: calibre_client,
+                db: calibre_db,
+            })),
+            archive_db: Arc::new(Mutex::new(archive_db)),
+            active_tasks: Arc::new(Mutex::new(HashMap::new())),
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            failed_task_retention: config.failed_task_retention(),
+            metadata_retry: config.metadata_retry(),
+            download_retry: config.download_retry(),
+            version_report: Arc::new(Mutex::new(None)),
         }
     }
 }
 
 #[tokio::main]
-async fn main() {
+async fn main() -> anyhow::Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
         .format_timestamp_secs()
         .format_target(false)
@@ -74,18 +119,44 @@ async fn main() {
 
     let config = Config::parse();
     let port = config.port();
+    let tag_refresh_interval = config.tag_refresh_interval();
+    let tag_refresh_update_metadata = config.tag_refresh_update_metadata();
+    let version_check_interval = config.version_check_interval();
+    let version_upgrade = config.version_upgrade();
     let download_manager = DownloadManager::new(config);
+    download_manager.init_tag_db().await?;
+    download_manager.resume_tasks().await;
+    download_manager.spawn_tag_refresh_job(tag_refresh_interval, tag_refresh_update_metadata);
+    download_manager.spawn_version_check_job(version_check_interval, version_upgrade);
 
     let app = Router::new()
         .route("/downloads", post(handle_download))
+        .route("/downloads/batch", post(handle_batch_download))
         .route("/tasks", get(get_active_tasks))
+        .route("/tasks/{id}", delete(handle_task_cancel))
+        .route("/events", get(handle_events))
         .route("/imports", post(handle_import))
         .route("/calibre/metadata", post(handle_metadata_update))
+        .route("/calibre/metadata/revert", post(handle_metadata_revert))
         .route(
             "/calibre/books/metadata",
             post(handle_book_metadata_replace),
         )
+        .route(
+            "/calibre/versions",
+            get(handle_version_report).post(handle_version_check),
+        )
         .route("/tags/query", post(handle_tag_query))
+        .route("/tags/query/batch", post(handle_tag_query_batch))
+        .route("/tags/search", post(handle_tag_search))
+        .route(
+            "/tags/overrides",
+            get(handle_tag_override_list).post(handle_tag_override_set),
+        )
+        .route(
+            "/tags/overrides/{namespace}/{raw_tag}",
+            delete(handle_tag_override_delete),
+        )
         .with_state(download_manager);
 
     let addr = format!("0.0.0.0:{port}");
@@ -93,4 +164,6 @@ async fn main() {
 
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
     axum::serve(listener, app).await.unwrap();
+
+    Ok(())
 }
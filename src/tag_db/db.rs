@@ -1,24 +1,53 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use anyhow::{Result, anyhow};
 use diesel::connection::Connection as DieselConnection;
 use diesel::prelude::*;
 use diesel::sql_query;
-use diesel::sql_types::Text;
+use diesel::sql_types::{BigInt, Nullable, Text};
 use diesel::sqlite::SqliteConnection;
 use diesel_dynamic_schema::table;
-use log::{debug, info};
+use log::{debug, info, warn};
+use tokio::sync::Mutex;
 
-use super::models::Metadata;
+use super::models::{Metadata, Namespace, NewNamespace, TagAlias};
+use super::schema::aliases::dsl as aliases_dsl;
 use super::schema::metadata::dsl as metadata_dsl;
+use super::schema::namespaces::dsl as namespaces_dsl;
+use super::schema::overrides::dsl as overrides_dsl;
 use super::{
-    ALPHA_REGEX, CHUNK_SIZE, DB_FILENAME, EhTagJson, GitHubTag, NAMESPACES, REPO, TagAction,
-    TagOperation, USER_AGENT,
+    ALPHA_REGEX, CHUNK_SIZE, DB_FILENAME, EhTagJson, FTS_MIN_QUERY_LEN, FTS_VERSION_KEY, GitHubTag,
+    MAX_ALIAS_DEPTH, NamespaceChanges, PreparedRelease, REPO, RESERVED_TABLES, TABLE_NAME_REGEX,
+    TagAction, TagDbSource, TagFormat, TagInfo, TagOperation, TagOverride, TagSearchHit, VARIANTS,
 };
+use crate::api::USER_AGENT;
+
+#[derive(QueryableByName)]
+struct ColumnInfo {
+    #[diesel(sql_type = Text)]
+    name: String,
+}
+
+#[derive(QueryableByName)]
+struct TagRecord {
+    #[diesel(sql_type = Text)]
+    raw: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    name: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    intro: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    links: Option<String>,
+}
+
+type ExistingRecord = (Option<String>, Option<String>, Option<String>);
 
 pub struct EhTagDb {
     conn: SqliteConnection,
+    db_path: PathBuf,
+    namespaces: Vec<Namespace>,
 }
 
 impl EhTagDb {
@@ -30,72 +59,277 @@ impl EhTagDb {
 
         let conn = SqliteConnection::establish(&db_path_str)?;
 
-        let mut db = Self { conn };
+        let mut db = Self {
+            conn,
+            db_path,
+            namespaces: Vec::new(),
+        };
         db.init()?;
         Ok(db)
     }
 
     fn init(&mut self) -> Result<()> {
-        let latest_tag = Self::get_latest_github_tag()?;
-        info!("Latest GitHub tag: {latest_tag}");
+        self.ensure_metadata_table_exists()?;
+        self.ensure_namespaces_table_exists()?;
+        self.ensure_overrides_table_exists()?;
+        self.ensure_aliases_table_exists()?;
+        self.load_namespaces()?;
 
-        let stored_version = self.get_stored_version()?;
+        let table_names: Vec<_> = self
+            .namespaces
+            .iter()
+            .map(|ns| ns.table_name.clone())
+            .collect();
+        for table_name in table_names {
+            self.ensure_table_exists(&table_name)?;
+        }
+
+        self.ensure_fts_table_exists()?;
+        self.ensure_fts_index_current()?;
+
+        Ok(())
+    }
+
+    /// Checks the source for a new EhTagTranslation release and applies it.
+    /// The database is only locked while reading the version and writing the
+    /// changes, not during the downloads or while comparing the release with
+    /// the stored tags. Returns whether the database was updated.
+    pub async fn refresh(tag_db: &Arc<Mutex<Self>>, source: &TagDbSource) -> Result<bool> {
+        let (version, json_data) = match source {
+            TagDbSource::GitHub => {
+                let latest_tag = Self::get_latest_github_tag().await?;
+                info!("Latest GitHub tag: {latest_tag}");
+                if !Self::is_outdated(tag_db, &latest_tag).await? {
+                    return Ok(false);
+                }
+
+                info!("Fetching JSON data from GitHub");
+                let url = Self::github_release_url(&latest_tag, "text");
+                let json_data = Self::fetch_json(&url).await?;
+                (latest_tag, json_data)
+            }
+            TagDbSource::File(path) => {
+                info!("Reading JSON from: {}", path.display());
+                let json_data: EhTagJson = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+                let version = json_data.version()?;
+                if !Self::is_outdated(tag_db, &version).await? {
+                    return Ok(false);
+                }
+                (version, json_data)
+            }
+            TagDbSource::Url(url) => {
+                // A mirror has no release list, so the whole file is needed for the version
+                let json_data = Self::fetch_json(url).await?;
+                let version = json_data.version()?;
+                if !Self::is_outdated(tag_db, &version).await? {
+                    return Ok(false);
+                }
+                (version, json_data)
+            }
+        };
+        info!("Successfully fetched JSON data");
+
+        let mut variants = Vec::with_capacity(VARIANTS.len());
+        for variant in VARIANTS {
+            // The text translations are enough to work with, the rest is optional
+            match Self::fetch_variant(source, &version, variant).await {
+                Ok(data) => variants.push((*variant, data)),
+                Err(e) => warn!("Failed to fetch {variant} tag data, skipping it: {e}"),
+            }
+        }
+
+        let (db_path, base_version) = {
+            let mut tag_db = tag_db.lock().await;
+            (tag_db.db_path.clone(), tag_db.get_stored_version()?)
+        };
+        let release = tokio::task::spawn_blocking(move || {
+            Self::prepare_release(&db_path, json_data, variants, version, base_version)
+        })
+        .await??;
+
+        let mut tag_db = tag_db.clone().lock_owned().await;
+        tokio::task::spawn_blocking(move || tag_db.apply_release(release)).await?
+    }
+
+    fn github_release_url(tag: &str, variant: &str) -> String {
+        format!("https://github.com/{REPO}/releases/download/{tag}/db.{variant}.json")
+    }
+
+    /// Variants are looked up next to the configured file or URL.
+    async fn fetch_variant(
+        source: &TagDbSource,
+        version: &str,
+        variant: &str,
+    ) -> Result<EhTagJson> {
+        let filename = format!("db.{variant}.json");
+        match source {
+            TagDbSource::GitHub => {
+                Self::fetch_json(&Self::github_release_url(version, variant)).await
+            }
+            TagDbSource::File(path) => {
+                let path = path.with_file_name(filename);
+                info!("Reading JSON from: {}", path.display());
+                Ok(serde_json::from_slice(&tokio::fs::read(path).await?)?)
+            }
+            TagDbSource::Url(url) => {
+                let (base, _) = url
+                    .rsplit_once('/')
+                    .ok_or_else(|| anyhow!("Invalid tag database URL: {}", url))?;
+                Self::fetch_json(&format!("{base}/{filename}")).await
+            }
+        }
+    }
+
+    async fn is_outdated(tag_db: &Mutex<Self>, latest_version: &str) -> Result<bool> {
+        let stored_version = tag_db.lock().await.get_stored_version()?;
 
         match stored_version {
-            Some(version) if version == latest_tag => {
+            Some(version) if version == latest_version => {
                 info!("Database is already at the latest version: {version}");
-                return Ok(());
+                Ok(false)
             }
             Some(version) => {
-                info!("Updating database from version {version} to {latest_tag}");
+                info!("Updating database from version {version} to {latest_version}");
+                Ok(true)
             }
             None => {
                 info!(
-                    "No version found in database, creating new database with version {latest_tag}"
+                    "No version found in database, creating new database with version {latest_version}"
                 );
+                Ok(true)
             }
         }
+    }
 
-        for namespace in NAMESPACES {
-            let table_name = if *namespace == "group" {
-                "groups"
-            } else {
-                namespace
+    /// Compares the release with the stored tags, reading through its own
+    /// connection.
+    fn prepare_release(
+        db_path: &Path,
+        mut json_data: EhTagJson,
+        mut variants: Vec<(&'static str, EhTagJson)>,
+        version: String,
+        base_version: Option<String>,
+    ) -> Result<PreparedRelease> {
+        let mut conn = SqliteConnection::establish(&db_path.to_string_lossy())?;
+        let table_names: HashMap<_, _> = namespaces_dsl::namespaces
+            .load::<Namespace>(&mut conn)?
+            .into_iter()
+            .map(|ns| (ns.namespace, ns.table_name))
+            .collect();
+
+        // Every namespace of the release is kept, new ones get their own table
+        let namespace_list: Vec<_> = json_data
+            .data
+            .iter()
+            .map(|ns| ns.namespace.clone())
+            .collect();
+
+        let mut namespaces = Vec::with_capacity(namespace_list.len());
+        for namespace in namespace_list {
+            info!("Processing namespace: {namespace}");
+            let table_name = table_names.get(&namespace);
+
+            let tag_list = Self::extract_tags_from_json(&mut json_data, &namespace)?;
+            let existing_records = match table_name {
+                Some(table_name) => Self::get_existing_tags(&mut conn, table_name, "")?,
+                None => HashMap::new(),
             };
-            self.ensure_table_exists(table_name)?;
+            let total = tag_list.len();
+            let operations: Vec<_> = Self::get_operations(tag_list, existing_records)
+                .into_iter()
+                .filter(|op| !matches!(op.operation, TagAction::Skip))
+                .collect();
+
+            let mut variant_changes = Vec::with_capacity(variants.len());
+            for (variant, variant_data) in &mut variants {
+                let tag_list = match Self::extract_tags_from_json(variant_data, &namespace) {
+                    Ok(tag_list) => tag_list,
+                    Err(e) => {
+                        warn!("Skipping {variant} data: {e}");
+                        continue;
+                    }
+                };
+                let existing_records = match table_name {
+                    Some(table_name) => {
+                        Self::get_existing_tags(&mut conn, table_name, &format!("_{variant}"))?
+                    }
+                    None => HashMap::new(),
+                };
+                let changed = tag_list
+                    .into_iter()
+                    .filter(|(raw, name, intro, links)| {
+                        !existing_records
+                            .get(raw)
+                            .is_some_and(|record| Self::is_same_tag(record, name, intro, links))
+                    })
+                    .collect();
+                variant_changes.push((*variant, changed));
+            }
+
+            namespaces.push(NamespaceChanges {
+                namespace,
+                skips: total - operations.len(),
+                operations,
+                variants: variant_changes,
+            });
         }
 
-        info!("Fetching JSON data from GitHub");
-        let mut json_data = Self::fetch_json_from_github(&latest_tag)?;
-        info!("Successfully fetched JSON data");
+        Ok(PreparedRelease {
+            base_version,
+            version,
+            namespaces,
+        })
+    }
 
-        for namespace in NAMESPACES {
-            info!("Processing namespace: {namespace}");
+    fn apply_release(&mut self, release: PreparedRelease) -> Result<bool> {
+        let stored_version = self.get_stored_version()?;
+        if stored_version != release.base_version {
+            warn!(
+                "Tag database changed to {stored_version:?} while preparing {}, skipping it",
+                release.version
+            );
+            return Ok(false);
+        }
 
-            let tag_list = Self::extract_tags_from_json(&mut json_data, namespace)?;
+        // execute_operations keeps an up to date index in sync on its own
+        let fts_in_sync = self.get_metadata_value(FTS_VERSION_KEY)? == stored_version;
 
+        for changes in release.namespaces {
+            let namespace = &changes.namespace;
+            self.ensure_namespace(namespace)?;
+
+            info!("Executing database operations for namespace {namespace}");
+            let (inserts, updates) = self.execute_operations(namespace, changes.operations)?;
             info!(
-                "Updating database with {} tags for namespace {}",
-                tag_list.len(),
-                namespace
+                "Inserts: {inserts}, Updates: {updates}, Skips: {}",
+                changes.skips
             );
 
-            self.update_namespace(namespace, tag_list)?;
+            for (variant, tag_list) in changes.variants {
+                self.update_namespace_variant(namespace, variant, tag_list)?;
+            }
         }
 
-        info!("Updating stored version to {latest_tag}");
-        self.update_stored_version(latest_tag)?;
-        Ok(())
+        info!("Updating stored version to {}", release.version);
+        self.update_stored_version(release.version.clone())?;
+
+        if fts_in_sync {
+            self.set_metadata_value(FTS_VERSION_KEY, release.version)?;
+        }
+        self.ensure_fts_index_current()?;
+        Ok(true)
     }
 
     pub fn get_tag_name(&mut self, namespace: &str, raw_tag: &str) -> Result<Option<String>> {
-        let table_name = if namespace == "group" {
-            "groups"
-        } else {
-            namespace
+        if let Some(name) = self.get_override(namespace, raw_tag)? {
+            return Ok(Some(name));
+        }
+
+        let Some(table_name) = self.table_name(namespace) else {
+            return Ok(None);
         };
 
-        let dyn_table = table(table_name);
+        let dyn_table = table(table_name.as_str());
         let raw_col = dyn_table.column::<Text, _>("raw");
         let name_col = dyn_table.column::<Text, _>("name");
 
@@ -108,66 +342,246 @@ impl EhTagDb {
         Ok(result)
     }
 
-    fn get_existing_tags(
+    /// Namespaces of gallery tags, i.e. all but the namespace translations.
+    pub fn tag_namespaces(&self) -> Vec<String> {
+        self.namespaces
+            .iter()
+            .filter(|ns| ns.namespace != "rows")
+            .map(|ns| ns.namespace.clone())
+            .collect()
+    }
+
+    /// Raw tags of the namespace currently translated as `name`, overrides
+    /// included.
+    pub fn find_raw_tags(&mut self, namespace: &str, name: &str) -> Result<Vec<String>> {
+        let mut raw_tags = overrides_dsl::overrides
+            .filter(overrides_dsl::namespace.eq(namespace))
+            .filter(overrides_dsl::name.eq(name))
+            .select(overrides_dsl::raw)
+            .load::<String>(&mut self.conn)?;
+
+        if let Some(table_name) = self.table_name(namespace) {
+            let dyn_table = table(table_name.as_str());
+            let raw_col = dyn_table.column::<Text, _>("raw");
+            let name_col = dyn_table.column::<Text, _>("name");
+            let upstream = dyn_table
+                .select(raw_col)
+                .filter(name_col.eq(name))
+                .load::<String>(&mut self.conn)?;
+            for raw_tag in upstream {
+                // An overridden tag no longer has its upstream name
+                if !raw_tags.contains(&raw_tag) && self.get_override(namespace, &raw_tag)?.is_none()
+                {
+                    raw_tags.push(raw_tag);
+                }
+            }
+        }
+
+        Ok(raw_tags)
+    }
+
+    /// Namespaces are translated by the `rows` table.
+    pub fn get_namespace_name(&mut self, namespace: &str) -> Result<Option<String>> {
+        self.get_tag_name("rows", namespace)
+    }
+
+    pub fn get_tag(
         &mut self,
         namespace: &str,
-        raw_values: Vec<&String>,
-    ) -> Result<HashMap<String, (String, String, String)>> {
-        let mut existing_records = HashMap::with_capacity(raw_values.len());
+        raw_tag: &str,
+        format: TagFormat,
+    ) -> Result<Option<TagInfo>> {
+        let Some(table_name) = self.table_name(namespace) else {
+            return Ok(self.get_override(namespace, raw_tag)?.map(|name| TagInfo {
+                name,
+                intro: None,
+                links: None,
+            }));
+        };
+        let suffix = format.column_suffix();
+
+        // Variants are missing if they couldn't be fetched with the release
+        let query = format!(
+            "SELECT COALESCE(name{suffix}, name) AS name,
+                COALESCE(intro{suffix}, intro) AS intro,
+                COALESCE(links{suffix}, links) AS links
+            FROM \"{table_name}\" WHERE raw = ?"
+        );
+
+        let result = sql_query(query)
+            .bind::<Text, _>(raw_tag)
+            .get_result::<TagInfo>(&mut self.conn)
+            .optional()?;
 
-        if raw_values.is_empty() {
-            return Ok(existing_records);
+        let Some(name) = self.get_override(namespace, raw_tag)? else {
+            return Ok(result);
+        };
+        Ok(Some(match result {
+            Some(tag) => TagInfo { name, ..tag },
+            None => TagInfo {
+                name,
+                intro: None,
+                links: None,
+            },
+        }))
+    }
+
+    /// Searches raw tags and translated names, and optionally intros, in all
+    /// namespaces. Hits are ranked exact match first, then prefix, substring
+    /// and intro matches, shorter names first within a rank.
+    pub fn search_tags(
+        &mut self,
+        query: &str,
+        include_intro: bool,
+        limit: usize,
+    ) -> Result<Vec<TagSearchHit>> {
+        let escaped = query
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        let prefix = format!("{escaped}%");
+        let substring = format!("%{escaped}%");
+        let rank = "CASE
+                WHEN name = ?1 OR raw = ?1 THEN 0
+                WHEN name LIKE ?2 ESCAPE '\\' OR raw LIKE ?2 ESCAPE '\\' THEN 1
+                WHEN name LIKE ?3 ESCAPE '\\' OR raw LIKE ?3 ESCAPE '\\' THEN 2
+                ELSE 3
+            END";
+
+        // The trigram index can't match anything shorter than three characters
+        if query.chars().count() >= FTS_MIN_QUERY_LEN {
+            let columns = if include_intro {
+                "{raw name intro}"
+            } else {
+                "{raw name}"
+            };
+            let fts_query = format!("{columns} : \"{}\"", query.replace('"', "\"\""));
+
+            let hits = sql_query(format!(
+                "SELECT namespace, raw, name, {rank} AS rank
+                FROM tags_fts
+                WHERE tags_fts MATCH ?4 AND namespace != 'rows'
+                ORDER BY rank, length(name)
+                LIMIT ?5"
+            ))
+            .bind::<Text, _>(query)
+            .bind::<Text, _>(&prefix)
+            .bind::<Text, _>(&substring)
+            .bind::<Text, _>(&fts_query)
+            .bind::<BigInt, _>(limit as i64)
+            .load::<TagSearchHit>(&mut self.conn)?;
+
+            return Ok(hits);
         }
 
-        let table_name = if namespace == "group" {
-            "groups"
+        let intro_filter = if include_intro {
+            " OR intro LIKE ?3 ESCAPE '\\'"
         } else {
-            namespace
+            ""
         };
 
-        let dyn_table = table(table_name);
-        let raw_col = dyn_table.column::<Text, _>("raw");
-        let name_col = dyn_table.column::<Text, _>("name");
-        let intro_col = dyn_table.column::<Text, _>("intro");
-        let links_col = dyn_table.column::<Text, _>("links");
+        let namespaces: Vec<_> = self
+            .namespaces
+            .iter()
+            .filter(|ns| ns.namespace != "rows")
+            .map(|ns| (ns.namespace.clone(), ns.table_name.clone()))
+            .collect();
+
+        let mut hits = Vec::new();
+        for (namespace, table_name) in namespaces {
+            let query_sql = format!(
+                "SELECT ?5 AS namespace, raw, name, {rank} AS rank
+                FROM (
+                    SELECT t.raw, COALESCE(o.name, t.name) AS name, t.intro
+                    FROM \"{table_name}\" t
+                    LEFT JOIN overrides o ON o.namespace = ?5 AND o.raw = t.raw
+                )
+                WHERE name LIKE ?3 ESCAPE '\\' OR raw LIKE ?3 ESCAPE '\\'{intro_filter}
+                ORDER BY rank, length(name)
+                LIMIT ?4"
+            );
 
-        for chunk in raw_values.chunks(CHUNK_SIZE) {
-            let results = dyn_table
-                .select((raw_col, name_col, intro_col, links_col))
-                .filter(raw_col.eq_any(chunk))
-                .load::<(String, String, String, String)>(&mut self.conn)?;
+            let results = sql_query(query_sql)
+                .bind::<Text, _>(query)
+                .bind::<Text, _>(&prefix)
+                .bind::<Text, _>(&substring)
+                .bind::<BigInt, _>(limit as i64)
+                .bind::<Text, _>(&namespace)
+                .load::<TagSearchHit>(&mut self.conn)?;
 
-            for (raw, name, intro, links) in results {
-                existing_records.insert(raw, (name, intro, links));
-            }
+            hits.extend(results);
         }
 
-        Ok(existing_records)
+        hits.sort_by(|a, b| {
+            (a.rank, a.name.chars().count(), &a.namespace, &a.raw).cmp(&(
+                b.rank,
+                b.name.chars().count(),
+                &b.namespace,
+                &b.raw,
+            ))
+        });
+        hits.truncate(limit);
+
+        Ok(hits)
+    }
+
+    /// Reads the base columns of a namespace table, or a variant's columns
+    /// given its `_{variant}` suffix.
+    fn get_existing_tags(
+        conn: &mut SqliteConnection,
+        table_name: &str,
+        suffix: &str,
+    ) -> Result<HashMap<String, ExistingRecord>> {
+        let records = sql_query(format!(
+            "SELECT raw, name{suffix} AS name, intro{suffix} AS intro, links{suffix} AS links
+            FROM \"{table_name}\""
+        ))
+        .load::<TagRecord>(conn)?;
+
+        Ok(records
+            .into_iter()
+            .map(|record| (record.raw, (record.name, record.intro, record.links)))
+            .collect())
+    }
+
+    fn is_same_tag(record: &ExistingRecord, name: &str, intro: &str, links: &str) -> bool {
+        record.0.as_deref() == Some(name)
+            && record.1.as_deref() == Some(intro)
+            && record.2.as_deref() == Some(links)
     }
 
-    fn update_namespace(
+    fn update_namespace_variant(
         &mut self,
         namespace: &str,
+        variant: &str,
         tag_list: Vec<(String, String, String, String)>,
     ) -> Result<()> {
-        info!("Updating namespace: {namespace}");
-
-        let mut raw_values = Vec::with_capacity(tag_list.len());
-        for tags in &tag_list {
-            raw_values.push(&tags.0);
-        }
-
-        info!("Getting existing tags for namespace {namespace}");
-        let existing_records = self.get_existing_tags(namespace, raw_values)?;
-        info!("Found {} existing records", existing_records.len());
+        let table_name = self.require_table_name(namespace)?;
 
-        info!("Determining operations needed");
-        let operations = Self::get_operations(tag_list, existing_records);
+        let update_sql = format!(
+            "UPDATE \"{table_name}\" SET name_{variant} = ?, intro_{variant} = ?, links_{variant} = ?
+            WHERE raw = ? AND (name_{variant} IS NOT ? OR intro_{variant} IS NOT ? OR links_{variant} IS NOT ?)"
+        );
 
-        info!("Executing database operations");
-        let (inserts, updates, skips) = self.execute_operations(namespace, operations)?;
+        let updates = self
+            .conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                let mut updates = 0;
+                for (raw, name, intro, links) in &tag_list {
+                    updates += sql_query(&update_sql)
+                        .bind::<Text, _>(name)
+                        .bind::<Text, _>(intro)
+                        .bind::<Text, _>(links)
+                        .bind::<Text, _>(raw)
+                        .bind::<Text, _>(name)
+                        .bind::<Text, _>(intro)
+                        .bind::<Text, _>(links)
+                        .execute(conn)?;
+                }
+                Ok(updates)
+            })?;
 
-        info!("Inserts: {inserts}, Updates: {updates}, Skips: {skips}");
+        info!("Updated {variant} data of {updates} tags for namespace {namespace}");
 
         Ok(())
     }
@@ -176,27 +590,23 @@ impl EhTagDb {
         &mut self,
         namespace: &str,
         operations: Vec<TagOperation>,
-    ) -> Result<(usize, usize, usize)> {
-        let total = operations.len();
+    ) -> Result<(usize, usize)> {
         let mut inserts = 0;
         let mut updates = 0;
-        let mut skips = 0;
 
-        let table_name = if namespace == "group" {
-            "groups"
-        } else {
-            namespace
-        };
+        let table_name = self.require_table_name(namespace)?;
+        let fts_offset = self.fts_rowid_offset(namespace)?;
 
         let (insert_ops, update_ops): (Vec<_>, Vec<_>) = operations
             .into_iter()
+            .filter(|op| !matches!(op.operation, TagAction::Skip))
             .partition(|op| matches!(op.operation, TagAction::Insert));
 
         self.conn
             .transaction::<_, diesel::result::Error, _>(|conn| {
                 for op in &insert_ops {
                     let insert_sql = format!(
-                        "INSERT INTO {table_name} (raw, name, intro, links) VALUES (?, ?, ?, ?)"
+                        "INSERT INTO \"{table_name}\" (raw, name, intro, links) VALUES (?, ?, ?, ?)"
                     );
 
                     sql_query(insert_sql)
@@ -205,11 +615,25 @@ impl EhTagDb {
                         .bind::<Text, _>(&op.intro)
                         .bind::<Text, _>(&op.links)
                         .execute(conn)?;
+
+                    // Overridden names stay in the index
+                    sql_query(
+                        "INSERT INTO tags_fts (rowid, namespace, raw, name, intro)
+                        VALUES (last_insert_rowid() + ?1, ?2, ?3,
+                            COALESCE((SELECT name FROM overrides WHERE namespace = ?2 AND raw = ?3), ?4),
+                            ?5)",
+                    )
+                    .bind::<BigInt, _>(fts_offset)
+                    .bind::<Text, _>(namespace)
+                    .bind::<Text, _>(&op.raw)
+                    .bind::<Text, _>(&op.name)
+                    .bind::<Text, _>(&op.intro)
+                    .execute(conn)?;
                 }
 
                 for op in &update_ops {
                     let update_sql = format!(
-                        "UPDATE {table_name} SET name = ?, intro = ?, links = ? WHERE raw = ?"
+                        "UPDATE \"{table_name}\" SET name = ?, intro = ?, links = ? WHERE raw = ?"
                     );
 
                     sql_query(update_sql)
@@ -218,21 +642,35 @@ impl EhTagDb {
                         .bind::<Text, _>(&op.links)
                         .bind::<Text, _>(&op.raw)
                         .execute(conn)?;
+
+                    let fts_update_sql = format!(
+                        "UPDATE tags_fts SET
+                            name = COALESCE((SELECT name FROM overrides WHERE namespace = ?1 AND raw = ?2), ?3),
+                            intro = ?4
+                        WHERE rowid = (SELECT id FROM \"{table_name}\" WHERE raw = ?2) + ?5"
+                    );
+
+                    sql_query(fts_update_sql)
+                        .bind::<Text, _>(namespace)
+                        .bind::<Text, _>(&op.raw)
+                        .bind::<Text, _>(&op.name)
+                        .bind::<Text, _>(&op.intro)
+                        .bind::<BigInt, _>(fts_offset)
+                        .execute(conn)?;
                 }
 
                 inserts = insert_ops.len();
                 updates = update_ops.len();
-                skips = total - inserts - updates;
 
                 Ok(())
             })?;
 
-        Ok((inserts, updates, skips))
+        Ok((inserts, updates))
     }
 
     fn ensure_table_exists(&mut self, table_name: &str) -> Result<()> {
         let create_table_sql = format!(
-            "CREATE TABLE IF NOT EXISTS {table_name} (
+            "CREATE TABLE IF NOT EXISTS \"{table_name}\" (
                 id INTEGER PRIMARY KEY AUTOINCREMENT, 
                 raw TEXT NOT NULL, 
                 name TEXT NOT NULL, 
@@ -244,6 +682,95 @@ impl EhTagDb {
 
         sql_query(create_table_sql).execute(&mut self.conn)?;
 
+        for variant in VARIANTS {
+            for column in ["name", "intro", "links"] {
+                self.ensure_column_exists(table_name, &format!("{column}_{variant}"), "TEXT")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn ensure_column_exists(
+        &mut self,
+        table_name: &str,
+        column: &str,
+        definition: &str,
+    ) -> Result<()> {
+        let columns = sql_query(format!("PRAGMA table_info(\"{table_name}\")"))
+            .load::<ColumnInfo>(&mut self.conn)?;
+
+        if !columns.iter().any(|c| c.name == column) {
+            info!("Adding column {column} to table {table_name}");
+            sql_query(format!(
+                "ALTER TABLE \"{table_name}\" ADD COLUMN {column} {definition}"
+            ))
+            .execute(&mut self.conn)?;
+        }
+
+        Ok(())
+    }
+
+    fn ensure_fts_table_exists(&mut self) -> Result<()> {
+        sql_query(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS tags_fts USING fts5(
+                namespace UNINDEXED,
+                raw,
+                name,
+                intro,
+                tokenize = 'trigram'
+            )",
+        )
+        .execute(&mut self.conn)?;
+
+        Ok(())
+    }
+
+    /// Tags of all namespaces share the index, so each namespace gets its own
+    /// rowid range on top of the tag ids.
+    fn fts_rowid_offset(&self, namespace: &str) -> Result<i64> {
+        self.namespaces
+            .iter()
+            .find(|ns| ns.namespace == namespace)
+            .map(|ns| i64::from(ns.id) << 32)
+            .ok_or_else(|| anyhow!("Unknown namespace: {}", namespace))
+    }
+
+    /// Rebuilds the index if it doesn't match the stored `github_tag` version.
+    fn ensure_fts_index_current(&mut self) -> Result<()> {
+        let version = self.get_stored_version()?;
+        let Some(version) = version else {
+            return Ok(());
+        };
+        if self.get_metadata_value(FTS_VERSION_KEY)?.as_ref() == Some(&version) {
+            return Ok(());
+        }
+
+        info!("Rebuilding tag search index for version {version}");
+        let namespaces = &self.namespaces;
+        self.conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                sql_query("DELETE FROM tags_fts").execute(conn)?;
+
+                for namespace in namespaces {
+                    sql_query(format!(
+                        "INSERT INTO tags_fts (rowid, namespace, raw, name, intro)
+                        SELECT t.id + ?1, ?2, t.raw, COALESCE(o.name, t.name), t.intro
+                        FROM \"{}\" t
+                        LEFT JOIN overrides o ON o.namespace = ?2 AND o.raw = t.raw",
+                        namespace.table_name
+                    ))
+                    .bind::<BigInt, _>(i64::from(namespace.id) << 32)
+                    .bind::<Text, _>(&namespace.namespace)
+                    .execute(conn)?;
+                }
+
+                Ok(())
+            })?;
+
+        self.set_metadata_value(FTS_VERSION_KEY, version)?;
+        info!("Tag search index rebuilt");
+
         Ok(())
     }
 
@@ -259,21 +786,360 @@ impl EhTagDb {
         Ok(())
     }
 
-    fn get_latest_github_tag() -> Result<String> {
+    /// Namespaces are registered with the table holding their tags, the id
+    /// also places them in the search index. Databases from before the
+    /// registry are registered with the tag tables they already have.
+    fn ensure_namespaces_table_exists(&mut self) -> Result<()> {
+        sql_query(
+            "CREATE TABLE IF NOT EXISTS namespaces (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                namespace TEXT NOT NULL UNIQUE,
+                table_name TEXT NOT NULL UNIQUE
+            )",
+        )
+        .execute(&mut self.conn)?;
+
+        let registered = namespaces_dsl::namespaces
+            .count()
+            .get_result::<i64>(&mut self.conn)?;
+        if registered > 0 {
+            return Ok(());
+        }
+
+        let tables = sql_query(
+            "SELECT name FROM sqlite_master
+            WHERE type = 'table' AND name NOT LIKE 'sqlite_%' AND name NOT LIKE 'tags_fts%'
+            ORDER BY name",
+        )
+        .load::<ColumnInfo>(&mut self.conn)?;
+
+        let mut migrated = 0;
+        for table_name in tables
+            .iter()
+            .map(|t| t.name.as_str())
+            .filter(|name| !RESERVED_TABLES.contains(name))
+        {
+            let namespace = if table_name == "groups" {
+                "group"
+            } else {
+                table_name
+            };
+            diesel::insert_into(namespaces_dsl::namespaces)
+                .values(&NewNamespace {
+                    namespace,
+                    table_name,
+                })
+                .execute(&mut self.conn)?;
+            migrated += 1;
+        }
+
+        if migrated > 0 {
+            info!("Registered {migrated} existing namespace tables");
+            // The index was laid out by the old namespace order
+            diesel::delete(metadata_dsl::metadata.filter(metadata_dsl::key.eq(FTS_VERSION_KEY)))
+                .execute(&mut self.conn)?;
+        }
+
+        Ok(())
+    }
+
+    fn load_namespaces(&mut self) -> Result<()> {
+        self.namespaces = namespaces_dsl::namespaces
+            .order(namespaces_dsl::id.asc())
+            .load::<Namespace>(&mut self.conn)?;
+
+        Ok(())
+    }
+
+    /// Registers a namespace seen in a release and creates its table.
+    fn ensure_namespace(&mut self, namespace: &str) -> Result<()> {
+        if self.table_name(namespace).is_some() {
+            return Ok(());
+        }
+
+        let table_name = self.new_table_name(namespace);
+        info!("Creating table {table_name} for new namespace {namespace}");
+        self.ensure_table_exists(&table_name)?;
+
+        diesel::insert_into(namespaces_dsl::namespaces)
+            .values(&NewNamespace {
+                namespace,
+                table_name: &table_name,
+            })
+            .execute(&mut self.conn)?;
+        self.load_namespaces()
+    }
+
+    /// Derives a table name from the namespace that is safe to use in SQL
+    /// and doesn't clash with other tables.
+    fn new_table_name(&self, namespace: &str) -> String {
+        if namespace == "group" {
+            return "groups".to_string();
+        }
+
+        let name = TABLE_NAME_REGEX
+            .replace_all(&namespace.to_lowercase(), "_")
+            .trim_matches('_')
+            .to_string();
+        let base = if name.is_empty()
+            || name.starts_with(|c: char| c.is_ascii_digit())
+            || name.starts_with("sqlite_")
+            || name == "groups"
+            || RESERVED_TABLES.contains(&name.as_str())
+        {
+            format!("ns_{name}")
+        } else {
+            name
+        };
+
+        let mut table_name = base.clone();
+        let mut suffix = 1;
+        while self.namespaces.iter().any(|ns| ns.table_name == table_name) {
+            suffix += 1;
+            table_name = format!("{base}_{suffix}");
+        }
+
+        table_name
+    }
+
+    fn table_name(&self, namespace: &str) -> Option<String> {
+        self.namespaces
+            .iter()
+            .find(|ns| ns.namespace == namespace)
+            .map(|ns| ns.table_name.clone())
+    }
+
+    fn require_table_name(&self, namespace: &str) -> Result<String> {
+        self.table_name(namespace)
+            .ok_or_else(|| anyhow!("Unknown namespace: {}", namespace))
+    }
+
+    /// Overrides live in their own table, so releases never touch them.
+    fn ensure_overrides_table_exists(&mut self) -> Result<()> {
+        sql_query(
+            "CREATE TABLE IF NOT EXISTS overrides (
+                namespace TEXT NOT NULL,
+                raw TEXT NOT NULL,
+                name TEXT NOT NULL,
+                PRIMARY KEY (namespace, raw)
+            )",
+        )
+        .execute(&mut self.conn)?;
+
+        Ok(())
+    }
+
+    fn get_override(&mut self, namespace: &str, raw_tag: &str) -> Result<Option<String>> {
+        let result = overrides_dsl::overrides
+            .filter(overrides_dsl::namespace.eq(namespace))
+            .filter(overrides_dsl::raw.eq(raw_tag))
+            .select(overrides_dsl::name)
+            .first::<String>(&mut self.conn)
+            .optional()?;
+
+        Ok(result)
+    }
+
+    pub fn get_overrides(&mut self) -> Result<Vec<TagOverride>> {
+        let result = overrides_dsl::overrides
+            .order((overrides_dsl::namespace.asc(), overrides_dsl::raw.asc()))
+            .load::<TagOverride>(&mut self.conn)?;
+
+        Ok(result)
+    }
+
+    /// Saves the override and shows it in the search index.
+    pub fn set_override(&mut self, tag: &TagOverride) -> Result<()> {
+        let fts_row = self.fts_row(&tag.namespace);
+        self.conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                diesel::insert_into(overrides_dsl::overrides)
+                    .values(tag)
+                    .on_conflict((overrides_dsl::namespace, overrides_dsl::raw))
+                    .do_update()
+                    .set(overrides_dsl::name.eq(&tag.name))
+                    .execute(conn)?;
+
+                if let Some((table_name, fts_offset)) = &fts_row {
+                    sql_query(format!(
+                        "UPDATE tags_fts SET name = ?
+                        WHERE rowid = (SELECT id FROM \"{table_name}\" WHERE raw = ?) + ?"
+                    ))
+                    .bind::<Text, _>(&tag.name)
+                    .bind::<Text, _>(&tag.raw)
+                    .bind::<BigInt, _>(fts_offset)
+                    .execute(conn)?;
+                }
+
+                Ok(())
+            })?;
+
+        Ok(())
+    }
+
+    /// Returns whether there was an override to delete. The search index
+    /// goes back to the upstream name.
+    pub fn delete_override(&mut self, namespace: &str, raw_tag: &str) -> Result<bool> {
+        let fts_row = self.fts_row(namespace);
+        let deleted = self
+            .conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                let deleted = diesel::delete(
+                    overrides_dsl::overrides
+                        .filter(overrides_dsl::namespace.eq(namespace))
+                        .filter(overrides_dsl::raw.eq(raw_tag)),
+                )
+                .execute(conn)?;
+
+                if let Some((table_name, fts_offset)) = fts_row.filter(|_| deleted > 0) {
+                    sql_query(format!(
+                        "UPDATE tags_fts SET name = (SELECT name FROM \"{table_name}\" WHERE raw = ?1)
+                        WHERE rowid = (SELECT id FROM \"{table_name}\" WHERE raw = ?1) + ?2"
+                    ))
+                    .bind::<Text, _>(raw_tag)
+                    .bind::<BigInt, _>(fts_offset)
+                    .execute(conn)?;
+                }
+
+                Ok(deleted)
+            })?;
+
+        Ok(deleted > 0)
+    }
+
+    /// The table and rowid offset of a namespace in the search index.
+    fn fts_row(&self, namespace: &str) -> Option<(String, i64)> {
+        let table_name = self.table_name(namespace)?;
+        let fts_offset = self.fts_rowid_offset(namespace).ok()?;
+        Some((table_name, fts_offset))
+    }
+
+    fn ensure_aliases_table_exists(&mut self) -> Result<()> {
+        sql_query(
+            "CREATE TABLE IF NOT EXISTS aliases (
+                namespace TEXT NOT NULL,
+                alias TEXT NOT NULL,
+                canonical TEXT NOT NULL,
+                PRIMARY KEY (namespace, alias)
+            )",
+        )
+        .execute(&mut self.conn)?;
+
+        Ok(())
+    }
+
+    /// Replaces the stored aliases with the ones from a JSON file mapping
+    /// each namespace to `{"alias": "canonical"}` pairs. Returns whether
+    /// the aliases changed.
+    pub async fn load_aliases(tag_db: &Mutex<Self>, path: &Path) -> Result<bool> {
+        info!("Reading tag aliases from: {}", path.display());
+        let json_data: HashMap<String, HashMap<String, String>> =
+            serde_json::from_slice(&tokio::fs::read(path).await?)?;
+
+        let mut aliases: Vec<_> = json_data
+            .into_iter()
+            .flat_map(|(namespace, names)| {
+                names
+                    .into_iter()
+                    .filter(|(alias, canonical)| alias != canonical)
+                    .map(move |(alias, canonical)| TagAlias {
+                        namespace: namespace.clone(),
+                        alias,
+                        canonical,
+                    })
+            })
+            .collect();
+        aliases.sort_by(|a, b| (&a.namespace, &a.alias).cmp(&(&b.namespace, &b.alias)));
+
+        tag_db.lock().await.replace_aliases(aliases)
+    }
+
+    fn replace_aliases(&mut self, aliases: Vec<TagAlias>) -> Result<bool> {
+        let existing = aliases_dsl::aliases
+            .order((aliases_dsl::namespace.asc(), aliases_dsl::alias.asc()))
+            .load::<TagAlias>(&mut self.conn)?;
+        if existing == aliases {
+            return Ok(false);
+        }
+
+        self.conn
+            .transaction::<_, diesel::result::Error, _>(|conn| {
+                diesel::delete(aliases_dsl::aliases).execute(conn)?;
+                for chunk in aliases.chunks(CHUNK_SIZE) {
+                    diesel::insert_into(aliases_dsl::aliases)
+                        .values(chunk)
+                        .execute(conn)?;
+                }
+                Ok(())
+            })?;
+        info!("Loaded {} tag aliases", aliases.len());
+
+        Ok(true)
+    }
+
+    /// Follows the alias chain of a raw tag to its canonical form.
+    pub fn resolve_alias(&mut self, namespace: &str, raw_tag: &str) -> Result<String> {
+        let mut raw_tag = raw_tag.to_string();
+
+        for _ in 0..MAX_ALIAS_DEPTH {
+            let canonical = aliases_dsl::aliases
+                .filter(aliases_dsl::namespace.eq(namespace))
+                .filter(aliases_dsl::alias.eq(&raw_tag))
+                .select(aliases_dsl::canonical)
+                .first::<String>(&mut self.conn)
+                .optional()?;
+            match canonical {
+                Some(canonical) => raw_tag = canonical,
+                None => break,
+            }
+        }
+
+        Ok(raw_tag)
+    }
+
+    /// Returns the canonical form of every alias by namespace.
+    pub fn get_aliases(&mut self) -> Result<HashMap<String, HashMap<String, String>>> {
+        let mut aliases: HashMap<String, HashMap<String, String>> = HashMap::new();
+        for tag in aliases_dsl::aliases.load::<TagAlias>(&mut self.conn)? {
+            aliases
+                .entry(tag.namespace)
+                .or_default()
+                .insert(tag.alias, tag.canonical);
+        }
+
+        for names in aliases.values_mut() {
+            let resolved: Vec<_> = names
+                .keys()
+                .map(|alias| {
+                    let mut canonical = &names[alias];
+                    for _ in 1..MAX_ALIAS_DEPTH {
+                        match names.get(canonical) {
+                            Some(next) => canonical = next,
+                            None => break,
+                        }
+                    }
+                    (alias.clone(), canonical.clone())
+                })
+                .collect();
+            names.extend(resolved);
+        }
+
+        Ok(aliases)
+    }
+
+    async fn get_latest_github_tag() -> Result<String> {
         let url = format!("https://api.github.com/repos/{REPO}/tags");
         info!("Fetching latest tag from: {url}");
 
-        let client = reqwest::blocking::Client::builder()
-            .user_agent(USER_AGENT)
-            .build()?;
+        let client = reqwest::Client::builder().user_agent(USER_AGENT).build()?;
 
-        let response = client.get(&url).send()?;
+        let response = client.get(&url).send().await?;
 
         if !response.status().is_success() {
             return Err(anyhow!("GitHub API returned status {}", response.status()));
         }
 
-        let tags: Vec<GitHubTag> = response.json()?;
+        let tags: Vec<GitHubTag> = response.json().await?;
 
         if tags.is_empty() {
             return Err(anyhow!("No tags found for repository {}", REPO));
@@ -283,11 +1149,17 @@ impl EhTagDb {
         Ok(first_tag.name)
     }
 
-    fn get_stored_version(&mut self) -> Result<Option<String>> {
-        self.ensure_metadata_table_exists()?;
+    pub fn get_stored_version(&mut self) -> Result<Option<String>> {
+        self.get_metadata_value("github_tag")
+    }
 
+    fn update_stored_version(&mut self, version: String) -> Result<()> {
+        self.set_metadata_value("github_tag", version)
+    }
+
+    fn get_metadata_value(&mut self, key: &str) -> Result<Option<String>> {
         let result = metadata_dsl::metadata
-            .filter(metadata_dsl::key.eq("github_tag"))
+            .filter(metadata_dsl::key.eq(key))
             .select(metadata_dsl::value)
             .first::<String>(&mut self.conn)
             .optional()?;
@@ -295,10 +1167,10 @@ impl EhTagDb {
         Ok(result)
     }
 
-    fn update_stored_version(&mut self, version: String) -> Result<()> {
+    fn set_metadata_value(&mut self, key: &str, value: String) -> Result<()> {
         let version_record = Metadata {
-            key: "github_tag".to_string(),
-            value: version,
+            key: key.to_string(),
+            value,
         };
 
         diesel::insert_into(metadata_dsl::metadata)
@@ -311,25 +1183,22 @@ impl EhTagDb {
         Ok(())
     }
 
-    fn fetch_json_from_github(tag: &str) -> Result<EhTagJson> {
-        let url = format!("https://github.com/{REPO}/releases/download/{tag}/db.text.json");
+    async fn fetch_json(url: &str) -> Result<EhTagJson> {
         info!("Fetching JSON from: {url}");
 
-        let client = reqwest::blocking::Client::builder()
-            .user_agent(USER_AGENT)
-            .build()?;
+        let client = reqwest::Client::builder().user_agent(USER_AGENT).build()?;
 
-        let response = client.get(&url).send()?;
+        let response = client.get(url).send().await?;
 
         if !response.status().is_success() {
             return Err(anyhow!(
-                "GitHub returned status {} for {}",
+                "Server returned status {} for {}",
                 response.status(),
                 url
             ));
         }
 
-        let json_data: EhTagJson = response.json()?;
+        let json_data: EhTagJson = response.json().await?;
 
         Ok(json_data)
     }
@@ -371,7 +1240,7 @@ impl EhTagDb {
 
     fn get_operations(
         tag_list: Vec<(String, String, String, String)>,
-        existing_records: HashMap<String, (String, String, String)>,
+        existing_records: HashMap<String, ExistingRecord>,
     ) -> Vec<TagOperation> {
         let mut operations = Vec::with_capacity(tag_list.len());
 
@@ -381,8 +1250,8 @@ impl EhTagDb {
             let intro = tags.2;
             let links = tags.3;
 
-            if let Some((db_name, db_intro, db_links)) = existing_records.get(&raw_value) {
-                if &name != db_name || &intro != db_intro || &links != db_links {
+            if let Some(record) = existing_records.get(&raw_value) {
+                if !Self::is_same_tag(record, &name, &intro, &links) {
                     operations.push(TagOperation {
                         raw: raw_value,
                         name,
@@ -416,14 +1285,8 @@ impl EhTagDb {
     pub fn get_all_tags(&mut self) -> Result<HashMap<String, HashMap<String, String>>> {
         let mut all_tags = HashMap::new();
 
-        for namespace in NAMESPACES {
-            let table_name = if *namespace == "group" {
-                "groups"
-            } else {
-                namespace
-            };
-
-            let dyn_table = table(table_name);
+        for namespace in &self.namespaces {
+            let dyn_table = table(namespace.table_name.as_str());
             let raw_col = dyn_table.column::<Text, _>("raw");
             let name_col = dyn_table.column::<Text, _>("name");
 
@@ -437,9 +1300,112 @@ impl EhTagDb {
                 tags_map.insert(raw, name);
             }
 
-            all_tags.insert(namespace.to_string(), tags_map);
+            all_tags.insert(namespace.namespace.clone(), tags_map);
+        }
+
+        for tag in self.get_overrides()? {
+            all_tags
+                .entry(tag.namespace)
+                .or_default()
+                .insert(tag.raw, tag.name);
         }
 
         Ok(all_tags)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn release(sha: &str, big_breasts: &str) -> String {
+        let tag = |name: &str| json!({ "name": name, "intro": "", "links": "" });
+        json!({
+            "head": { "sha": sha },
+            "data": [
+                { "namespace": "rows", "data": { "female": tag("女性"), "artist": tag("艺术家") } },
+                { "namespace": "female", "data": { "big breasts": tag(big_breasts) } },
+                { "namespace": "artist", "data": { "some artist": tag("某画师") } },
+            ],
+        })
+        .to_string()
+    }
+
+    /// A database in a fresh directory, with `db.text.json` as its source.
+    fn temp_tag_db() -> (Arc<Mutex<EhTagDb>>, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("eh_tag_db_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tag_db = EhTagDb::new(dir.to_string_lossy().into_owned()).unwrap();
+        (Arc::new(Mutex::new(tag_db)), dir)
+    }
+
+    #[tokio::test]
+    async fn bootstraps_from_file() {
+        let (tag_db, dir) = temp_tag_db();
+        let source_path = dir.join("db.text.json");
+        let source = TagDbSource::File(source_path.clone());
+        std::fs::write(&source_path, release("v1", "巨乳")).unwrap();
+
+        assert!(EhTagDb::refresh(&tag_db, &source).await.unwrap());
+        {
+            let mut db = tag_db.lock().await;
+            assert_eq!(db.get_stored_version().unwrap().as_deref(), Some("v1"));
+            assert_eq!(
+                db.get_tag_name("female", "big breasts").unwrap().as_deref(),
+                Some("巨乳")
+            );
+            assert_eq!(
+                db.get_namespace_name("artist").unwrap().as_deref(),
+                Some("艺术家")
+            );
+            assert_eq!(db.get_tag_name("female", "unknown").unwrap(), None);
+        }
+        assert!(!EhTagDb::refresh(&tag_db, &source).await.unwrap());
+
+        std::fs::write(&source_path, release("v2", "大乳房")).unwrap();
+        assert!(EhTagDb::refresh(&tag_db, &source).await.unwrap());
+        {
+            let mut db = tag_db.lock().await;
+            assert_eq!(db.get_stored_version().unwrap().as_deref(), Some("v2"));
+            assert_eq!(
+                db.get_tag_name("female", "big breasts").unwrap().as_deref(),
+                Some("大乳房")
+            );
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_stored_version_without_source() {
+        let (tag_db, dir) = temp_tag_db();
+        let source_path = dir.join("db.text.json");
+        std::fs::write(&source_path, release("v1", "巨乳")).unwrap();
+        assert!(
+            EhTagDb::refresh(&tag_db, &TagDbSource::File(source_path.clone()))
+                .await
+                .unwrap()
+        );
+
+        std::fs::remove_file(&source_path).unwrap();
+        let missing = TagDbSource::File(source_path);
+        assert!(EhTagDb::refresh(&tag_db, &missing).await.is_err());
+        let mut db = tag_db.lock().await;
+        assert_eq!(db.get_stored_version().unwrap().as_deref(), Some("v1"));
+        assert_eq!(
+            db.get_tag_name("female", "big breasts").unwrap().as_deref(),
+            Some("巨乳")
+        );
+        drop(db);
+
+        // Nothing to fall back on in a new database
+        let (empty_db, empty_dir) = temp_tag_db();
+        assert!(EhTagDb::refresh(&empty_db, &missing).await.is_err());
+        assert_eq!(empty_db.lock().await.get_stored_version().unwrap(), None);
+
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(empty_dir).unwrap();
+    }
+}
@@ -2,43 +2,101 @@ pub mod db;
 mod models;
 mod schema;
 
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf, str::FromStr};
 
+use anyhow::{Result, anyhow};
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::Deserialize;
 
-const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/121.0.0.0 Safari/537.36 Edg/121.0.0.0";
+pub use models::{TagInfo, TagOverride, TagSearchHit};
+
 const DB_FILENAME: &str = "eh_tag.db";
 const CHUNK_SIZE: usize = 500;
+const FTS_MIN_QUERY_LEN: usize = 3;
+const FTS_VERSION_KEY: &str = "fts_version";
 const REPO: &str = "EhTagTranslation/Database";
-const NAMESPACES: &[&str] = &[
-    "artist",
-    "character",
-    "cosplayer",
-    "female",
-    "group",
-    "language",
-    "male",
-    "mixed",
-    "other",
-    "parody",
-    "reclass",
-    "rows",
-];
+/// Tables that can't be used for a namespace.
+const RESERVED_TABLES: &[&str] = &["aliases", "metadata", "namespaces", "overrides", "tags_fts"];
+/// Bounds alias chains, in case the alias file has a cycle.
+const MAX_ALIAS_DEPTH: usize = 8;
+
+/// Release variants stored next to the plain text translations.
+const VARIANTS: &[&str] = &["raw", "html"];
 
 static ALPHA_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"[A-Za-z]").unwrap());
+static TABLE_NAME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"[^a-z0-9_]+").unwrap());
 
 #[derive(Debug, Deserialize)]
 struct GitHubTag {
     name: String,
 }
 
+/// Where tag translation releases come from: the GitHub releases, a local
+/// `db.text.json` / `db.raw.json` or a mirror URL of one.
+#[derive(Debug, Clone)]
+pub enum TagDbSource {
+    GitHub,
+    File(PathBuf),
+    Url(String),
+}
+
+impl FromStr for TagDbSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "github" {
+            Ok(Self::GitHub)
+        } else if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(Self::Url(s.to_string()))
+        } else {
+            Ok(Self::File(PathBuf::from(s)))
+        }
+    }
+}
+
+/// The rendering of a tag's name, intro and links, `Markdown` is the upstream
+/// raw format.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TagFormat {
+    #[default]
+    Text,
+    Markdown,
+    Html,
+}
+
+impl TagFormat {
+    const fn column_suffix(&self) -> &'static str {
+        match self {
+            Self::Text => "",
+            Self::Markdown => "_raw",
+            Self::Html => "_html",
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct EhTagJson {
+    head: Option<EhTagHead>,
     data: Vec<EhTagNamespace>,
 }
 
+impl EhTagJson {
+    /// Releases outside GitHub are versioned by the database commit.
+    fn version(&self) -> Result<String> {
+        self.head
+            .as_ref()
+            .map(|head| head.sha.clone())
+            .ok_or_else(|| anyhow!("No head.sha found in JSON data"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct EhTagHead {
+    sha: String,
+}
+
 #[derive(Debug, Deserialize)]
 struct EhTagNamespace {
     namespace: String,
@@ -67,3 +125,24 @@ enum TagAction {
     Update,
     Skip,
 }
+
+/// `raw`, `name`, `intro` and `links` of a tag in a release.
+type TagEntry = (String, String, String, String);
+
+/// The changes a release makes to the database, worked out on a separate
+/// connection so the database stays usable meanwhile.
+#[derive(Debug)]
+struct PreparedRelease {
+    /// The version the changes were worked out against.
+    base_version: Option<String>,
+    version: String,
+    namespaces: Vec<NamespaceChanges>,
+}
+
+#[derive(Debug)]
+struct NamespaceChanges {
+    namespace: String,
+    operations: Vec<TagOperation>,
+    skips: usize,
+    variants: Vec<(&'static str, Vec<TagEntry>)>,
+}
@@ -1,4 +1,5 @@
 use diesel::prelude::*;
+use diesel::sql_types::{Integer, Nullable, Text};
 
 use super::schema::*;
 
@@ -8,3 +9,56 @@ pub struct Metadata {
     pub key: String,
     pub value: String,
 }
+
+#[derive(Queryable, Debug, Clone)]
+#[diesel(table_name = namespaces)]
+pub struct Namespace {
+    pub id: i32,
+    pub namespace: String,
+    pub table_name: String,
+}
+
+#[derive(Insertable, Debug, Clone)]
+#[diesel(table_name = namespaces)]
+pub struct NewNamespace<'a> {
+    pub namespace: &'a str,
+    pub table_name: &'a str,
+}
+
+#[derive(Queryable, Insertable, Debug, Clone)]
+#[diesel(table_name = overrides)]
+pub struct TagOverride {
+    pub namespace: String,
+    pub raw: String,
+    pub name: String,
+}
+
+#[derive(Queryable, Insertable, Debug, Clone, PartialEq, Eq)]
+#[diesel(table_name = aliases)]
+pub struct TagAlias {
+    pub namespace: String,
+    pub alias: String,
+    pub canonical: String,
+}
+
+#[derive(QueryableByName, Debug, Clone)]
+pub struct TagInfo {
+    #[diesel(sql_type = Text)]
+    pub name: String,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub intro: Option<String>,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub links: Option<String>,
+}
+
+#[derive(QueryableByName, Debug, Clone)]
+pub struct TagSearchHit {
+    #[diesel(sql_type = Text)]
+    pub namespace: String,
+    #[diesel(sql_type = Text)]
+    pub raw: String,
+    #[diesel(sql_type = Text)]
+    pub name: String,
+    #[diesel(sql_type = Integer)]
+    pub rank: i32,
+}
@@ -1,27 +1,3 @@
-macro_rules! define_tag_table {
-    ($table_name:ident) => {
-        diesel::table! {
-            $table_name (id) {
-                id -> Integer,
-                raw -> Text,
-                name -> Text,
-                intro -> Text,
-                links -> Text,
-            }
-        }
-    };
-    ($($table_name:ident),+) => {
-        $(
-            define_tag_table!($table_name);
-        )+
-    };
-}
-
-define_tag_table!(
-    artist, character, cosplayer, female, groups, language, male, mixed, other, parody, reclass,
-    rows
-);
-
 diesel::table! {
     metadata (key) {
         key -> Text,
@@ -29,7 +5,28 @@ diesel::table! {
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(
-    artist, character, cosplayer, female, groups, language, male, mixed, other, parody, reclass,
-    rows, metadata,
-);
+diesel::table! {
+    namespaces (id) {
+        id -> Integer,
+        namespace -> Text,
+        table_name -> Text,
+    }
+}
+
+diesel::table! {
+    overrides (namespace, raw) {
+        namespace -> Text,
+        raw -> Text,
+        name -> Text,
+    }
+}
+
+diesel::table! {
+    aliases (namespace, alias) {
+        namespace -> Text,
+        alias -> Text,
+        canonical -> Text,
+    }
+}
+
+diesel::allow_tables_to_appear_in_same_query!(metadata, namespaces, overrides, aliases);
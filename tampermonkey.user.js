@@ -629,20 +629,22 @@
         activeTasks.forEach(task => {
             const taskItem = document.createElement('div');
 
-            let displayText = task;
+            let displayText = task.url || task.kind;
 
-            if (task.includes('/g/')) {
-                const parts = task.split('/');
-                const gIndex = parts.indexOf('g');
+            if (task.gid && task.token) {
+                displayText = `${task.gid}_${task.token}`;
+            }
 
-                if (gIndex !== -1 && gIndex + 2 < parts.length) {
-                    const gid = parts[gIndex + 1];
-                    const token = parts[gIndex + 2];
-                    displayText = `${gid}_${token}`;
-                }
+            let stateText = task.state;
+            if (task.state === 'downloading' && task.bytes_total) {
+                const percent = Math.min(100, Math.floor(task.bytes_received * 100 / task.bytes_total));
+                stateText = `${task.state} ${percent}%`;
             }
 
-            taskItem.textContent = `• ${displayText}`;
+            taskItem.textContent = `• ${displayText} (${stateText})`;
+            if (task.error) {
+                taskItem.title = task.error;
+            }
             taskItem.style.overflow = 'hidden';
             taskItem.style.textOverflow = 'ellipsis';
             taskItem.style.whiteSpace = 'nowrap';